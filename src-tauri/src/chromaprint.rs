@@ -0,0 +1,597 @@
+//! Chromaprint compatible fingerprints, for cross-checking identifications
+//! against AcoustID and other Chromaprint tooling.
+//!
+//! This is a port of Chromaprint's default algorithm (`TEST2`): the audio is
+//! resampled to 11025 Hz with the same fixed point resampler, turned into 12
+//! band chroma features, smoothed, normalised, and classified by 16 Haar-like
+//! filters into one 32 bit sub-fingerprint per frame. For the same 16 bit mono
+//! PCM the result matches `chromaprint_get_fingerprint`, up to floating point
+//! differences between FFT implementations on values sitting exactly on a
+//! quantiser threshold.
+
+use anyhow::{Result, bail};
+use realfft::RealFftPlanner;
+
+use crate::utils::{MonoDecoder, open_binary};
+
+/// Sample rate the audio is analysed at.
+pub const SAMPLE_RATE: u32 = 11025;
+/// Leading part of the audio fingerprinted by default, the same as `fpcalc`
+/// so fingerprints line up with the ones stored by AcoustID.
+pub const MAX_DURATION_SECS: u32 = 120;
+
+/// Algorithm id written in the header of compressed fingerprints.
+const ALGORITHM: u8 = 1;
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+const NUM_BANDS: usize = 12;
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+/// Silent frames are not normalised.
+const MIN_NORM: f64 = 0.01;
+
+/// Size of the input and output buffers of the resampler.
+const BUFFER_SIZE: usize = 1024 * 32;
+const RESAMPLE_FILTER_LENGTH: usize = 16;
+const RESAMPLE_PHASE_SHIFT: u32 = 8;
+const RESAMPLE_CUTOFF: f64 = 0.8;
+/// Kaiser window parameter of the resampling filter.
+const RESAMPLE_WINDOW: f64 = 9.0;
+const FILTER_SHIFT: u32 = 15;
+const MIN_SAMPLE_RATE: u32 = 1000;
+
+/// Haar-like filter over the chroma image as `(type, first band, bands, frames)`
+/// with the thresholds quantising its output.
+struct Classifier {
+    filter: (u8, usize, usize, usize),
+    thresholds: [f64; 3],
+}
+
+const fn classifier(filter: (u8, usize, usize, usize), thresholds: [f64; 3]) -> Classifier {
+    Classifier { filter, thresholds }
+}
+
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier((0, 4, 3, 15), [1.98215, 2.35817, 2.63523]),
+    classifier((4, 4, 6, 15), [-1.03809, -0.651211, -0.282167]),
+    classifier((1, 0, 4, 16), [-0.298702, 0.119262, 0.558497]),
+    classifier((3, 8, 2, 12), [-0.105439, 0.0153946, 0.135898]),
+    classifier((3, 4, 4, 8), [-0.142891, 0.0258736, 0.200632]),
+    classifier((4, 0, 3, 5), [-0.826319, -0.590612, -0.368214]),
+    classifier((1, 2, 2, 9), [-0.557409, -0.233035, 0.0534525]),
+    classifier((2, 7, 3, 4), [-0.0646826, 0.00620476, 0.0784847]),
+    classifier((2, 6, 2, 16), [-0.192387, -0.029699, 0.215855]),
+    classifier((2, 1, 3, 2), [-0.0397818, -0.00568076, 0.0292026]),
+    classifier((5, 10, 1, 15), [-0.53823, -0.369934, -0.190235]),
+    classifier((3, 6, 2, 10), [-0.124877, 0.0296483, 0.139239]),
+    classifier((2, 1, 1, 14), [-0.101475, 0.0225617, 0.231971]),
+    classifier((3, 5, 6, 4), [-0.0799915, -0.00729616, 0.063262]),
+    classifier((1, 9, 2, 12), [-0.272556, 0.019424, 0.302559]),
+    classifier((3, 4, 2, 14), [-0.164292, -0.0321188, 0.08463]),
+];
+
+/// Frames covered by the widest classifier, one sub-fingerprint is emitted per
+/// frame once this many chroma frames are available.
+const MAX_FILTER_WIDTH: usize = 16;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Compressed, base64 encoded Chromaprint fingerprint of the first
+/// [`MAX_DURATION_SECS`] of an encoded audio file held in memory, the string
+/// `fpcalc` prints and AcoustID accepts.
+pub fn chromaprint_bytes(bytes: Vec<u8>) -> Result<String> {
+    let (pcm, sr) = decode_pcm(bytes)?;
+    Ok(encode_fingerprint(&chromaprint_pcm(pcm, sr)?))
+}
+
+/// The first [`MAX_DURATION_SECS`] of an encoded file as 16 bit mono PCM,
+/// downmixed the way Chromaprint does, with its sample rate.
+fn decode_pcm(bytes: Vec<u8>) -> Result<(Vec<i16>, u32)> {
+    let mut decoder = MonoDecoder::new(open_binary(bytes)?)?;
+    let sr = decoder.sample_rate();
+    let limit = MAX_DURATION_SECS as usize * sr as usize;
+    let mut pcm = vec![];
+    while pcm.len() < limit {
+        let Some(samples) = decoder.next_pcm_chunk()? else {
+            break;
+        };
+        pcm.extend_from_slice(&samples[..samples.len().min(limit - pcm.len())]);
+    }
+    decoder.finish()?;
+    Ok((pcm, sr))
+}
+
+/// Raw sub-fingerprints of mono samples in `-1.0..=1.0` at `sample_rate`.
+pub fn chromaprint(samples: &[f32], sample_rate: u32) -> Result<Vec<u32>> {
+    // Chromaprint works on 16 bit PCM, which symphonia scales by 1 / 32768
+    let pcm = samples
+        .iter()
+        .map(|&x| {
+            (x * 32768.0)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect();
+    chromaprint_pcm(pcm, sample_rate)
+}
+
+/// Raw sub-fingerprints of 16 bit mono PCM at `sample_rate`.
+pub fn chromaprint_pcm(pcm: Vec<i16>, sample_rate: u32) -> Result<Vec<u32>> {
+    if sample_rate < MIN_SAMPLE_RATE {
+        bail!("Sample rate {} is too low for Chromaprint", sample_rate);
+    }
+    let pcm = if sample_rate == SAMPLE_RATE {
+        pcm
+    } else {
+        AvResampler::new(SAMPLE_RATE, sample_rate).resample(&pcm)
+    };
+    Ok(sub_fingerprints(&chroma_features(&pcm)))
+}
+
+/// Compresses raw sub-fingerprints the way `chromaprint_encode_fingerprint` does,
+/// then base64 encodes them with the URL safe alphabet and no padding.
+pub fn encode_fingerprint(fingerprint: &[u32]) -> String {
+    encode_base64(&compress(fingerprint))
+}
+
+/// Chromaprint's compressed layout: the algorithm and the number of
+/// sub-fingerprints, then the positions of the bits that changed from one
+/// sub-fingerprint to the next as 3 bit deltas, with deltas of 7 and more
+/// continued in a second section of 5 bit values.
+fn compress(fingerprint: &[u32]) -> Vec<u8> {
+    let len = fingerprint.len();
+    let mut output = vec![ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    let mut deltas = vec![];
+    let mut last = 0u32;
+    for &sub in fingerprint {
+        let (mut changed, mut bit, mut last_bit) = (sub ^ last, 1u32, 0u32);
+        while changed != 0 {
+            if changed & 1 == 1 {
+                deltas.push(bit - last_bit);
+                last_bit = bit;
+            }
+            changed >>= 1;
+            bit += 1;
+        }
+        deltas.push(0);
+        last = sub;
+    }
+
+    let mut writer = BitWriter::default();
+    for &delta in &deltas {
+        writer.write(delta.min(7), 3);
+    }
+    output.extend(writer.finish());
+    let mut writer = BitWriter::default();
+    for &delta in deltas.iter().filter(|&&delta| delta >= 7) {
+        writer.write(delta - 7, 5);
+    }
+    output.extend(writer.finish());
+    output
+}
+
+/// Packs values least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            output.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    output
+}
+
+/// Smoothed and normalised chroma vector of every frame of audio at [`SAMPLE_RATE`].
+fn chroma_features(pcm: &[i16]) -> Vec<[f64; NUM_BANDS]> {
+    let fft = RealFftPlanner::<f64>::new().plan_fft_forward(FRAME_SIZE);
+    let window = (0..FRAME_SIZE)
+        .map(|i| {
+            let phase = 2.0 * std::f64::consts::PI * i as f64 / (FRAME_SIZE - 1) as f64;
+            (0.54 - 0.46 * phase.cos()) / i16::MAX as f64
+        })
+        .collect::<Vec<f64>>();
+
+    let to_index = |freq: f64| (FRAME_SIZE as f64 * freq / SAMPLE_RATE as f64).round() as usize;
+    let (min_index, max_index) = (
+        to_index(MIN_FREQ).max(1),
+        to_index(MAX_FREQ).min(FRAME_SIZE / 2),
+    );
+    // pitch class of every FFT bin, with A0 at the start of an octave
+    let notes = (min_index..max_index)
+        .map(|i| {
+            let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (freq / (440.0 / 16.0)).ln() / 2f64.ln();
+            (NUM_BANDS as f64 * (octave - octave.floor())) as usize
+        })
+        .collect::<Vec<usize>>();
+
+    let mut spectrum = fft.make_output_vec();
+    let mut chroma = vec![];
+    for start in (0..).map(|k| k * HOP_SIZE) {
+        let Some(frame) = pcm.get(start..start + FRAME_SIZE) else {
+            break;
+        };
+        let mut input = frame
+            .iter()
+            .zip(&window)
+            .map(|(&x, &w)| x as f64 * w)
+            .collect::<Vec<f64>>();
+        fft.process(&mut input, &mut spectrum).unwrap();
+        let mut features = [0.0; NUM_BANDS];
+        for (i, &note) in (min_index..max_index).zip(&notes) {
+            features[note] += spectrum[i].norm_sqr();
+        }
+        chroma.push(features);
+    }
+
+    chroma
+        .windows(CHROMA_FILTER.len())
+        .map(|frames| {
+            let mut features = [0.0; NUM_BANDS];
+            for (band, value) in features.iter_mut().enumerate() {
+                for (frame, coefficient) in frames.iter().zip(CHROMA_FILTER) {
+                    *value += frame[band] * coefficient;
+                }
+            }
+            let norm = features.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm < MIN_NORM {
+                [0.0; NUM_BANDS]
+            } else {
+                features.map(|x| x / norm)
+            }
+        })
+        .collect()
+}
+
+/// Classifies every [`MAX_FILTER_WIDTH`] frames of the chroma image into a
+/// sub-fingerprint, two gray coded bits per classifier.
+fn sub_fingerprints(features: &[[f64; NUM_BANDS]]) -> Vec<u32> {
+    let image = IntegralImage::new(features);
+    (0..(features.len() + 1).saturating_sub(MAX_FILTER_WIDTH))
+        .map(|offset| {
+            CLASSIFIERS.iter().fold(0u32, |bits, classifier| {
+                let value = image.filter(classifier.filter, offset);
+                let [t0, t1, t2] = classifier.thresholds;
+                let quantised = match value {
+                    v if v < t0 => 0,
+                    v if v < t1 => 1,
+                    v if v < t2 => 2,
+                    _ => 3,
+                };
+                (bits << 2) | [0, 1, 3, 2][quantised]
+            })
+        })
+        .collect()
+}
+
+/// Running sums over frames and bands of the chroma image.
+struct IntegralImage {
+    rows: Vec<[f64; NUM_BANDS]>,
+}
+
+impl IntegralImage {
+    fn new(features: &[[f64; NUM_BANDS]]) -> Self {
+        let mut rows: Vec<[f64; NUM_BANDS]> = Vec::with_capacity(features.len());
+        for features in features {
+            let mut row = [0.0; NUM_BANDS];
+            let mut sum = 0.0;
+            for (cell, &x) in row.iter_mut().zip(features) {
+                sum += x;
+                *cell = sum;
+            }
+            if let Some(last) = rows.last() {
+                for (cell, &above) in row.iter_mut().zip(last) {
+                    *cell += above;
+                }
+            }
+            rows.push(row);
+        }
+        Self { rows }
+    }
+
+    /// Sum over frames `r1..r2` and bands `c1..c2`.
+    fn area(&self, r1: usize, c1: usize, r2: usize, c2: usize) -> f64 {
+        if r1 == r2 || c1 == c2 {
+            return 0.0;
+        }
+        let row2 = &self.rows[r2 - 1];
+        match (r1, c1) {
+            (0, 0) => row2[c2 - 1],
+            (0, _) => row2[c2 - 1] - row2[c1 - 1],
+            (_, 0) => row2[c2 - 1] - self.rows[r1 - 1][c2 - 1],
+            _ => {
+                let row1 = &self.rows[r1 - 1];
+                row2[c2 - 1] - row1[c2 - 1] - row2[c1 - 1] + row1[c1 - 1]
+            }
+        }
+    }
+
+    /// Log ratio between the two halves of a Haar-like filter placed at frame `x`.
+    fn filter(&self, (kind, y, h, w): (u8, usize, usize, usize), x: usize) -> f64 {
+        let area = |r1, c1, r2, c2| self.area(r1, c1, r2, c2);
+        let (a, b) = match kind {
+            0 => (area(x, y, x + w, y + h), 0.0),
+            1 => {
+                let h2 = h / 2;
+                (area(x, y + h2, x + w, y + h), area(x, y, x + w, y + h2))
+            }
+            2 => {
+                let w2 = w / 2;
+                (area(x + w2, y, x + w, y + h), area(x, y, x + w2, y + h))
+            }
+            3 => {
+                let (w2, h2) = (w / 2, h / 2);
+                (
+                    area(x, y + h2, x + w2, y + h) + area(x + w2, y, x + w, y + h2),
+                    area(x, y, x + w2, y + h2) + area(x + w2, y + h2, x + w, y + h),
+                )
+            }
+            4 => {
+                let h3 = h / 3;
+                (
+                    area(x, y + h3, x + w, y + 2 * h3),
+                    area(x, y, x + w, y + h3) + area(x, y + 2 * h3, x + w, y + h),
+                )
+            }
+            _ => {
+                let w3 = w / 3;
+                (
+                    area(x + w3, y, x + 2 * w3, y + h),
+                    area(x, y, x + w3, y + h) + area(x + 2 * w3, y, x + w, y + h),
+                )
+            }
+        };
+        ((1.0 + a) / (1.0 + b)).ln()
+    }
+}
+
+/// Port of the fixed point polyphase resampler Chromaprint takes from libavcodec,
+/// including how it buffers its input.
+struct AvResampler {
+    filter_bank: Vec<i16>,
+    filter_length: usize,
+    index: i64,
+    frac: i64,
+    src_incr: i64,
+    dst_incr: i64,
+}
+
+impl AvResampler {
+    fn new(out_rate: u32, in_rate: u32) -> Self {
+        let factor = (out_rate as f64 * RESAMPLE_CUTOFF / in_rate as f64).min(1.0);
+        let phase_count = 1usize << RESAMPLE_PHASE_SHIFT;
+        let filter_length = ((RESAMPLE_FILTER_LENGTH as f64 / factor).ceil() as usize).max(1);
+        Self {
+            filter_bank: build_filter(factor, filter_length, phase_count),
+            filter_length,
+            index: -((phase_count * ((filter_length - 1) / 2)) as i64),
+            frac: 0,
+            src_incr: out_rate as i64,
+            dst_incr: in_rate as i64 * phase_count as i64,
+        }
+    }
+
+    /// Resamples the whole input, fed through a buffer of [`BUFFER_SIZE`] samples.
+    fn resample(mut self, pcm: &[i16]) -> Vec<i16> {
+        let mut output = vec![];
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        let mut input = pcm;
+        while !input.is_empty() {
+            let loaded = (BUFFER_SIZE - buffer.len()).min(input.len());
+            buffer.extend_from_slice(&input[..loaded]);
+            input = &input[loaded..];
+            if buffer.len() == BUFFER_SIZE {
+                let consumed = self.process(&buffer, &mut output);
+                buffer.drain(..consumed);
+                if buffer.len() == BUFFER_SIZE {
+                    break;
+                }
+            }
+        }
+        if !buffer.is_empty() {
+            self.process(&buffer, &mut output);
+        }
+        output
+    }
+
+    /// Produces up to [`BUFFER_SIZE`] samples from `src`, returns how many input samples were consumed.
+    fn process(&mut self, src: &[i16], dst: &mut Vec<i16>) -> usize {
+        let (mut index, mut frac) = (self.index, self.frac);
+        let (dst_incr, dst_incr_frac) =
+            (self.dst_incr / self.src_incr, self.dst_incr % self.src_incr);
+        let phase_mask = (1i64 << RESAMPLE_PHASE_SHIFT) - 1;
+        for _ in 0..BUFFER_SIZE {
+            let phase = (index & phase_mask) as usize;
+            let filter = &self.filter_bank[self.filter_length * phase..][..self.filter_length];
+            let sample_index = index >> RESAMPLE_PHASE_SHIFT;
+            let mut val = 0i32;
+            if sample_index < 0 {
+                // the start of the stream is mirrored
+                for (i, &coefficient) in filter.iter().enumerate() {
+                    let j = (sample_index + i as i64).unsigned_abs() as usize % src.len();
+                    val = val.wrapping_add(src[j] as i32 * coefficient as i32);
+                }
+            } else if sample_index as usize + self.filter_length > src.len() {
+                break;
+            } else {
+                let samples = &src[sample_index as usize..][..self.filter_length];
+                for (&x, &coefficient) in samples.iter().zip(filter) {
+                    val = val.wrapping_add(x as i32 * coefficient as i32);
+                }
+            }
+            val = (val + (1 << (FILTER_SHIFT - 1))) >> FILTER_SHIFT;
+            dst.push(val.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+
+            frac += dst_incr_frac;
+            index += dst_incr;
+            if frac >= self.src_incr {
+                frac -= self.src_incr;
+                index += 1;
+            }
+        }
+        let consumed = (index.max(0) >> RESAMPLE_PHASE_SHIFT) as usize;
+        if index >= 0 {
+            index &= phase_mask;
+        }
+        self.index = index;
+        self.frac = frac;
+        consumed
+    }
+}
+
+/// Kaiser windowed sinc filter bank with one set of taps per phase, scaled to `1 << FILTER_SHIFT`.
+fn build_filter(factor: f64, tap_count: usize, phase_count: usize) -> Vec<i16> {
+    use std::f64::consts::PI;
+    let center = (tap_count - 1) / 2;
+    let scale = (1 << FILTER_SHIFT) as f64;
+    let mut bank = Vec::with_capacity(tap_count * phase_count);
+    for phase in 0..phase_count {
+        let taps = (0..tap_count)
+            .map(|i| {
+                let x =
+                    PI * ((i as f64 - center as f64) - phase as f64 / phase_count as f64) * factor;
+                let y = if x == 0.0 { 1.0 } else { x.sin() / x };
+                let w = 2.0 * x / (factor * tap_count as f64 * PI);
+                y * bessel(RESAMPLE_WINDOW * (1.0 - w * w).max(0.0).sqrt())
+            })
+            .collect::<Vec<f64>>();
+        let norm = taps.iter().sum::<f64>();
+        bank.extend(taps.iter().map(|&y| {
+            let tap = ((y * scale / norm) as f32).round_ties_even() as i32;
+            tap.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        }));
+    }
+    bank
+}
+
+/// Modified Bessel function of the first kind of order zero.
+fn bessel(x: f64) -> f64 {
+    let x = x * x / 4.0;
+    let (mut v, mut last, mut t) = (1.0, 0.0, 1.0);
+    let mut i = 1.0;
+    while v != last {
+        last = v;
+        t *= x / (i * i);
+        v += t;
+        i += 1.0;
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// 16 bit WAV of interleaved `samples`.
+    fn wav(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(vec![]);
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for &x in samples {
+            writer.write_sample(x).unwrap();
+        }
+        writer.finalize().unwrap();
+        wav.into_inner()
+    }
+
+    /// Expected values from the silence test of Chromaprint's own API tests.
+    #[test]
+    fn silence_matches_chromaprint() {
+        let silence = vec![0.0; 130 * 1024];
+        let fingerprint = chromaprint(&silence, 44100).unwrap();
+        assert_eq!(fingerprint, vec![627964279; 3]);
+        assert_eq!(encode_fingerprint(&fingerprint), "AQAAA0mUaEkSRZEGAA");
+
+        let wav = wav(&vec![0; 130 * 1024], 1, 44100);
+        assert_eq!(chromaprint_bytes(wav).unwrap(), "AQAAA0mUaEkSRZEGAA");
+    }
+
+    /// Exercises the filters and the quantiser on a 100 Hz to 3 kHz chirp at
+    /// 44.1 kHz. The expected value is the output of this port, pinned so
+    /// changes show up, it has not been cross-checked with `fpcalc -raw`.
+    #[test]
+    fn chirp_fingerprint_is_stable() {
+        let (sr, secs) = (44100, 5.0);
+        let samples = (0..(sr as f64 * secs) as usize)
+            .map(|n| {
+                let t = n as f64 / sr as f64;
+                // the frequency rises linearly from 100 Hz to 3 kHz
+                let phase = 2.0 * std::f64::consts::PI * (100.0 * t + 290.0 * t * t);
+                (phase.sin() * 16000.0) as i16
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chromaprint_bytes(wav(&samples, 1, sr)).unwrap(),
+            "AQAAE0mihEqWpEqC54ezRPkEqXoy5EfzHCWVI08fQ_qdDDdxhNnRnjjt4qI8_BPh5Efk3NC_A0GAUNQBiQQRQHDsiAIEKYYAAA"
+        );
+    }
+
+    #[test]
+    fn downmixes_like_chromaprint() {
+        // Chromaprint averages the channels in integers, rounding towards zero
+        let wav = wav(&[1, 2, -1, -2, 3, -4, i16::MAX, i16::MAX], 2, SAMPLE_RATE);
+        let (pcm, sr) = decode_pcm(wav).unwrap();
+        assert_eq!(sr, SAMPLE_RATE);
+        assert_eq!(pcm, [1, -1, 0, i16::MAX]);
+    }
+
+    #[test]
+    fn encodes_like_chromaprint() {
+        assert_eq!(compress(&[1, 0]), vec![ALGORITHM, 0, 0, 2, 65, 0]);
+        assert_eq!(encode_fingerprint(&[1, 0]), "AQAAAkEA");
+    }
+
+    #[test]
+    fn only_reads_max_duration() {
+        let limit = MAX_DURATION_SECS as usize * SAMPLE_RATE as usize;
+        let mut samples = vec![0; limit];
+        samples.extend((0..SAMPLE_RATE as usize).map(|n| {
+            let t = n as f32 / SAMPLE_RATE as f32;
+            ((2.0 * std::f32::consts::PI * 440.0 * t).sin() * 16000.0) as i16
+        }));
+        let expected = encode_fingerprint(&chromaprint(&vec![0.0; limit], SAMPLE_RATE).unwrap());
+        assert_eq!(
+            chromaprint_bytes(wav(&samples, 1, SAMPLE_RATE)).unwrap(),
+            expected
+        );
+    }
+}
@@ -1,17 +1,20 @@
 #![allow(unused)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::{Result, anyhow};
 use redis::{
-    AsyncCommands, Client, ClientTlsConfig, TlsCertificates, aio::ConnectionManager,
+    AsyncCommands, Client, ClientTlsConfig, TlsCertificates,
+    aio::{ConnectionManager, ConnectionManagerConfig},
     from_redis_value,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, from_str, to_string};
 
+use crate::fingerprint::FingerprintConfig;
+use crate::metadata::Cover;
+use crate::retry::RetryConfig;
 use crate::schema::TrackResult;
-use crate::utils::ANCHOR_POINTS;
 
 // #[derive(Deserialize, Serialize, Debug)]
 // struct SongInfo {
@@ -92,19 +95,31 @@ pub struct DownloadUrl {
     pub url: String,
 }
 
-pub async fn connect_redis() -> Result<ConnectionManager> {
-    let uri = env!("REDIS_URI");
-    // let client = Client::open(uri)?;
-    let client = Client::build_with_tls(
-        uri,
-        TlsCertificates {
-            client_tls: None,
-            root_cert: None,
-        },
-    )?;
-    Ok(client.get_connection_manager().await?)
+/// Connects once, retrying is left to `retry` so a failing connection is given
+/// up on after its attempts rather than the connection manager's own.
+pub async fn connect_redis(uri: &str, retry: &RetryConfig) -> Result<ConnectionManager> {
+    let client = if uri.starts_with("rediss://") {
+        Client::build_with_tls(
+            uri,
+            TlsCertificates {
+                client_tls: None,
+                root_cert: None,
+            },
+        )?
+    } else {
+        Client::open(uri)?
+    };
+    let config = ConnectionManagerConfig::new()
+        .set_number_of_retries(0)
+        .set_connection_timeout(retry.timeout())
+        .set_response_timeout(retry.timeout());
+    Ok(client.get_connection_manager_with_config(config).await?)
 }
 
+/// Set once [`build_reverse_index`] has indexed the songs stored before the
+/// `song_hashes:{id}` reverse index existed.
+const REVERSE_INDEX_KEY: &str = "fingerprint:reverse_index";
+
 // ------------------------------------------------------------------------------------------------------
 
 pub async fn get_redis_song(
@@ -123,6 +138,33 @@ pub async fn get_redis_song(
         .collect())
 }
 
+pub async fn get_redis_songs(
+    client: &mut ConnectionManager,
+    keys: &[u64],
+) -> Result<Vec<Vec<(u64, String)>>> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in keys {
+        pipe.smembers(key.to_string());
+    }
+    let all_members: Vec<Vec<String>> = pipe
+        .query_async(client)
+        .await
+        .map_err(|e| anyhow!("Failed to get songs from keys: {:?}", e))?;
+    Ok(all_members
+        .into_iter()
+        .map(|members| {
+            members
+                .into_iter()
+                .filter_map(|song| {
+                    song.split_once("|")
+                        .and_then(|(k, v)| Some((k.parse::<u64>().ok()?, v.to_string())))
+                })
+                .collect()
+        })
+        .collect())
+}
+
 pub async fn set_redis_song(
     client: &mut ConnectionManager,
     key: u64,
@@ -135,15 +177,30 @@ pub async fn set_redis_song(
         .map_err(|e| anyhow!("Failed to set song in Redis: {}", e))?)
 }
 
+/// Adds every `(hash, time, id)` as a `time|id` member of the hash key, a hash
+/// repeated within a song keeps one member per time it occurs at.
 pub async fn set_all_songs(
     client: &mut ConnectionManager,
     songs: Vec<(u64, u64, &str)>,
     song_data: TrackResult,
+    hash_version: u8,
+    cover: Option<&Cover>,
 ) -> Result<()> {
     let mut pipe = redis::pipe();
     pipe.atomic();
     for song in songs {
         pipe.sadd(song.0.to_string(), format!("{}|{}", song.1, song.2));
+        pipe.sadd(
+            format!("song_hashes:{}", song.2),
+            format!("{}|{}", song.0, song.1),
+        );
+    }
+    pipe.set(format!("song_version:{}", song_data.id), hash_version);
+    if let Some(cover) = cover {
+        pipe.hset_multiple(
+            format!("cover:{}", song_data.id),
+            &[("type", cover.media_type.as_bytes()), ("data", &cover.data[..])],
+        );
     }
     pipe.set(format!("song:{}", song_data.id), to_string(&song_data)?);
     Ok(pipe.query_async(client).await?)
@@ -173,6 +230,115 @@ pub async fn delete_redis_json(client: &mut ConnectionManager, key: &str) -> Res
     Ok(client.del(format!("song:{}", key)).await?)
 }
 
+pub async fn get_redis_jsons(
+    client: &mut ConnectionManager,
+    keys: &[String],
+) -> Result<Vec<TrackResult>> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in keys {
+        pipe.get(format!("song:{}", key));
+    }
+
+    let values: Vec<Option<String>> = pipe
+        .query_async(client)
+        .await
+        .map_err(|e| anyhow!("Failed to get result songs: {:?}", e))?;
+    let mut songs = Vec::with_capacity(keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        match value {
+            Some(json_str) => match serde_json::from_str::<TrackResult>(&json_str) {
+                Ok(song) => songs.push(song),
+                Err(e) => return Err(anyhow!("Failed to parse song {}: {}", key, e)),
+            },
+            None => return Err(anyhow!("No value found for key: {}", key)),
+        }
+    }
+    Ok(songs)
+}
+
+/// Hash layout version of every song from its `song_version:{id}` key.
+pub async fn get_redis_hash_versions(
+    client: &mut ConnectionManager,
+    ids: &[String],
+) -> Result<Vec<Option<u8>>> {
+    let mut pipe = redis::pipe();
+    for id in ids {
+        pipe.get(format!("song_version:{}", id));
+    }
+    Ok(pipe.query_async(client).await?)
+}
+
+/// Links `alias` to the song `id` with an `alias:{alias}` key, remembered in the
+/// `song_aliases:{id}` set so the aliases go away with the song.
+pub async fn set_redis_alias(client: &mut ConnectionManager, alias: &str, id: &str) -> Result<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.set(format!("alias:{}", alias), id);
+    pipe.sadd(format!("song_aliases:{}", id), alias);
+    Ok(pipe.query_async(client).await?)
+}
+
+pub async fn get_redis_alias(client: &mut ConnectionManager, alias: &str) -> Result<Option<String>> {
+    Ok(client.get(format!("alias:{}", alias)).await?)
+}
+
+pub async fn delete_redis_aliases(client: &mut ConnectionManager, id: &str) -> Result<()> {
+    let index_key = format!("song_aliases:{}", id);
+    let aliases = client.smembers::<&str, Vec<String>>(&index_key).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for alias in aliases {
+        pipe.del(format!("alias:{}", alias));
+    }
+    pipe.del(&index_key);
+    Ok(pipe.query_async(client).await?)
+}
+
+/// Stores embedded artwork in a `cover:{id}` hash of its `type` and `data`.
+pub async fn set_redis_cover(
+    client: &mut ConnectionManager,
+    id: &str,
+    cover: &Cover,
+) -> Result<()> {
+    Ok(client
+        .hset_multiple(
+            format!("cover:{}", id),
+            &[("type", cover.media_type.as_bytes()), ("data", &cover.data[..])],
+        )
+        .await?)
+}
+
+pub async fn get_redis_cover(client: &mut ConnectionManager, id: &str) -> Result<Option<Cover>> {
+    let (media_type, data): (Option<String>, Option<Vec<u8>>) = redis::cmd("HMGET")
+        .arg(format!("cover:{}", id))
+        .arg("type")
+        .arg("data")
+        .query_async(client)
+        .await?;
+    Ok(media_type
+        .zip(data)
+        .map(|(media_type, data)| Cover { media_type, data }))
+}
+
+pub async fn get_redis_config(client: &mut ConnectionManager) -> Result<Option<FingerprintConfig>> {
+    let value = client.get::<&str, Option<String>>("fingerprint:config").await?;
+    Ok(value.map(|value| from_str(&value)).transpose()?)
+}
+
+/// Stores the database config, a database without any keys is also marked as
+/// indexed since it never held songs stored before the reverse index.
+pub async fn set_redis_config(client: &mut ConnectionManager, config: &FingerprintConfig) -> Result<()> {
+    let size: usize = redis::cmd("DBSIZE").query_async(client).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.set("fingerprint:config", to_string(config)?);
+    if size == 0 {
+        pipe.set(REVERSE_INDEX_KEY, 1);
+    }
+    Ok(pipe.query_async(client).await?)
+}
+
 pub async fn get_all_songs(client: &mut ConnectionManager) -> Result<Vec<TrackResult>> {
     let mut pipe = redis::pipe();
 
@@ -201,7 +367,43 @@ pub async fn get_all_songs(client: &mut ConnectionManager) -> Result<Vec<TrackRe
     Ok(songs)
 }
 
+/// Deletes a song using its `song_hashes:{id}` reverse index, a set of `hash|time`
+/// members pointing at every `time|id` entry the song added to a hash key.
 pub async fn delete_song(client: &mut ConnectionManager, id: &str) -> Result<()> {
+    let index_key = format!("song_hashes:{}", id);
+    let members = client.smembers::<&str, Vec<String>>(&index_key).await?;
+    if members.is_empty() && is_unindexed(client, id).await? {
+        return delete_song_by_scan(client, id).await;
+    }
+
+    let mut delete_pipe = redis::pipe();
+    delete_pipe.atomic();
+    for member in members {
+        if let Some((key, time)) = member.split_once("|") {
+            delete_pipe.srem(key, format!("{}|{}", time, id));
+        }
+    }
+    delete_pipe.del(&index_key);
+    delete_pipe.del(format!("song_version:{}", id));
+    delete_pipe.del(format!("cover:{}", id));
+    delete_pipe.del(format!("song:{}", id));
+    delete_pipe.query_async::<()>(client).await?;
+    Ok(())
+}
+
+/// Whether `id` is a stored song whose hash entries may be missing from the reverse
+/// index, because it was stored before the index existed and the database has not
+/// been migrated since.
+async fn is_unindexed(client: &mut ConnectionManager, id: &str) -> Result<bool> {
+    let (migrated, exists): (bool, bool) = redis::pipe()
+        .exists(REVERSE_INDEX_KEY)
+        .exists(format!("song:{}", id))
+        .query_async(client)
+        .await?;
+    Ok(exists && !migrated)
+}
+
+async fn delete_song_by_scan(client: &mut ConnectionManager, id: &str) -> Result<()> {
     let keys = client.keys::<&str, Vec<String>>("[0-9]*").await?;
 
     let mut get_pipe = redis::pipe();
@@ -221,137 +423,44 @@ pub async fn delete_song(client: &mut ConnectionManager, id: &str) -> Result<()>
             }
         }
     }
+    delete_pipe.del(format!("song_version:{}", id));
+    delete_pipe.del(format!("cover:{}", id));
     delete_pipe.query_async::<()>(client).await?;
 
     delete_redis_json(client, id).await?;
     Ok(())
 }
 
-// ------------------------------------------------------------------------------------------------------
-
-pub async fn get_similar_songs(
-    client: &mut ConnectionManager,
-    keys: HashMap<u64, (u64, &str)>,
-) -> Result<Vec<TrackResult>> {
-    let mut point_counts = HashMap::new();
-    let mut anchors = HashMap::new();
-    let mut song_times = HashMap::new();
-    let mut main_song_id = "".to_string();
-
-    let mut pipe = redis::pipe();
-    pipe.atomic();
-    for key in keys.iter() {
-        pipe.smembers(key.0.to_string());
-    }
-    let all_members: Vec<Vec<String>> = pipe
-        .query_async(client)
-        .await
-        .map_err(|e| anyhow!("Failed to get songs from keys: {:?}", e))?;
-
-    for ((key, (orig_time, orig_song_id)), members) in keys.into_iter().zip(all_members) {
-        main_song_id = orig_song_id.to_string();
-        members
-            .into_iter()
-            .filter_map(|v| {
-                v.split_once("|")
-                    .and_then(|(k, v)| Some((k.parse::<u64>().ok()?, v.to_string())))
-            })
-            .for_each(|(time, song_id)| {
-                point_counts
-                    .entry(song_id.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(0u8);
-                anchors
-                    .entry(song_id.clone())
-                    .and_modify(|count_map: &mut HashMap<u64, u8>| {
-                        count_map
-                            .entry(time)
-                            .and_modify(|count| *count += 1)
-                            .or_insert(1u8);
-                    })
-                    .or_insert(HashMap::new());
-                song_times
-                    .entry(song_id.clone())
-                    .and_modify(|vec: &mut Vec<(u64, u64)>| vec.push((key, time)))
-                    .or_insert(vec![(key, time)]);
-                song_times
-                    .entry(orig_song_id.to_string())
-                    .and_modify(|vec: &mut Vec<(u64, u64)>| vec.push((key, orig_time)))
-                    .or_insert(vec![(key, orig_time)]);
-            });
+/// Builds the `song_hashes:{id}` reverse index from the existing hash keys,
+/// returns the number of songs indexed.
+pub async fn build_reverse_index(client: &mut ConnectionManager) -> Result<usize> {
+    let mut keys = vec![];
+    {
+        let mut iter = client.scan_match::<&str, String>("[0-9]*").await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
     }
-    let orig_song_time = song_times
-        .get(&main_song_id)
-        .ok_or(anyhow!("No song time found"))?;
 
-    let mut song_ids = anchors
-        .into_iter()
-        .filter_map(|(song_id, anchors)| {
-            let anchor_count = anchors
-                .iter()
-                .filter(|(_, count)| **count as usize == ANCHOR_POINTS - 1)
-                .count();
-            let point_count = point_counts.get(&song_id).unwrap_or(&0);
-
-            // if count < 10 {
-            //     return None;
-            // }
-            if let Some(song_time) = song_times.get(&song_id) {
-                let time_diffs = song_time
-                    .into_iter()
-                    .zip(orig_song_time.iter())
-                    .filter_map(|((k, time), (o_k, orig_time))| {
-                        if k == o_k {
-                            Some((*time, *orig_time))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<(u64, u64)>>();
-                let mut time_diff = 0;
-                time_diffs.windows(2).for_each(|window| {
-                    let (time, orig_time) = window[0];
-                    let (o_time, o_orig_time) = window[1];
-                    let diff1 = (time as i64 - o_time as i64).abs();
-                    let diff2 = (orig_time as i64 - o_orig_time as i64).abs();
-                    time_diff += if (diff1 - diff2).abs() < 100 { 1 } else { 0 };
-                });
-                Some((time_diff, anchor_count, point_count, song_id))
-            } else {
-                None
+    let mut songs = HashSet::new();
+    for chunk in keys.chunks(1000) {
+        let mut get_pipe = redis::pipe();
+        for key in chunk {
+            get_pipe.smembers(key);
+        }
+        let all_members: Vec<Vec<String>> = get_pipe.query_async(client).await?;
+
+        let mut index_pipe = redis::pipe();
+        for (key, members) in chunk.iter().zip(all_members) {
+            for member in members {
+                if let Some((time, id)) = member.split_once("|") {
+                    index_pipe.sadd(format!("song_hashes:{}", id), format!("{}|{}", key, time));
+                    songs.insert(id.to_string());
+                }
             }
-        })
-        .collect::<Vec<_>>();
-
-    song_ids.sort_by_key(|(time_diff, anchor_count, point_count, _)| (*time_diff, *anchor_count, *point_count));
-
-    // println!("Song IDs: {:#?}", song_ids);
-    let mut songs = vec![];
-    let song_ids = song_ids
-        .into_iter()
-        .rev()
-        .take(3)
-        .map(|(_, _, _, song_id)| song_id)
-        .collect::<Vec<_>>();
-
-    let mut pipe = redis::pipe();
-    pipe.atomic();
-    for song_id in &song_ids {
-        pipe.get(format!("song:{}", song_id));
-    }
-
-    let values: Vec<Option<String>> = pipe
-        .query_async(client)
-        .await
-        .map_err(|e| anyhow!("Failed to get result songs: {:?}", e))?;
-    for (song_id, value) in song_ids.iter().zip(values) {
-        match value {
-            Some(json_str) => match serde_json::from_str::<TrackResult>(&json_str) {
-                Ok(song) => songs.push(song),
-                Err(e) => return Err(anyhow!("Failed to parse song {}: {}", song_id, e)),
-            },
-            None => return Err(anyhow!("No value found for key: {}", song_id)),
         }
+        index_pipe.query_async::<()>(client).await?;
     }
-    Ok(songs)
+    client.set::<_, _, ()>(REVERSE_INDEX_KEY, 1).await?;
+    Ok(songs.len())
 }
@@ -0,0 +1,563 @@
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+use anyhow::Result;
+use realfft::{RealFftPlanner, RealToComplex};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::{HASH_VERSION, RATIO_HASH_VERSION};
+use crate::peaks::PeakPicker;
+use crate::resample::Resampler;
+use crate::utils::*;
+
+/// Version of the fingerprint pipeline, bumped whenever a change alters the hashes
+/// produced for the same config.
+pub const PIPELINE_VERSION: u32 = 5;
+
+/// Peaks further apart than this in time are never paired as nearest neighbours,
+/// bounds the peaks kept in memory.
+const PAIRING_WINDOW_MS: usize = 1000;
+
+/// Parameters of the fingerprint pipeline.
+///
+/// Hashes are only comparable when both sides were computed with the same
+/// config, so the config used for ingestion is stored alongside the database.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FingerprintConfig {
+    /// Pipeline version the config was stored with, configs from before versioning read as `0`.
+    #[serde(default)]
+    pub version: u32,
+    pub fft_size: usize,
+    pub hop_size: usize,
+    /// Frequency bands in Hz, the loudest bin of every band is a peak candidate.
+    pub bands: Vec<(usize, usize)>,
+    /// Number of peaks every anchor peak is paired with.
+    pub fan_out: usize,
+    /// Minimum magnitude of a peak in the normalised spectrogram.
+    pub peak_threshold: f32,
+    /// Sample rate every input is resampled to before analysis.
+    pub target_sample_rate: u32,
+    /// How peaks are picked from the spectrogram, configs stored before it existed use band maxima.
+    #[serde(default)]
+    pub peak_strategy: PeakStrategy,
+    /// How anchor peaks are paired into hashes, configs stored before it existed use nearest neighbours.
+    #[serde(default)]
+    pub pairing_strategy: PairingStrategy,
+    /// Frequency axis peaks are picked on, configs stored before it existed use the linear FFT bins.
+    #[serde(default)]
+    pub frequency_scale: FrequencyScale,
+}
+
+/// Frequency axis of the spectrogram the peaks are picked on.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FrequencyScale {
+    /// FFT bins, with the band edges of [`FingerprintConfig::bands`].
+    #[default]
+    Linear,
+    /// Triangular mel filters over the FFT bins, with bands of equal width on the
+    /// mel scale in place of [`FingerprintConfig::bands`].
+    #[serde(rename_all = "camelCase")]
+    Mel {
+        /// Number of mel filters.
+        filters: usize,
+        /// Lower edge of the first filter in Hz.
+        min_freq: f32,
+        /// Upper edge of the last filter in Hz.
+        max_freq: f32,
+        /// Number of bands the filters are split into for band maxima.
+        bands: usize,
+    },
+}
+
+impl FrequencyScale {
+    /// Mel scale covering the default analysis sample rate.
+    pub fn mel() -> Self {
+        Self::Mel {
+            filters: 64,
+            min_freq: 20.0,
+            max_freq: (DEFAULT_SAMPLE_RATE / 4) as f32,
+            bands: BANDS.len(),
+        }
+    }
+}
+
+/// Peak picking strategy of the fingerprint pipeline.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PeakStrategy {
+    /// Loudest bin of every band per frame, keeping those above the frame average.
+    #[default]
+    BandMax,
+    /// Local maxima of the spectrogram over a time × frequency neighbourhood.
+    #[serde(rename_all = "camelCase")]
+    Constellation {
+        /// Neighbourhood half width in frames.
+        time_radius: usize,
+        /// Neighbourhood half height in bins of the frequency axis.
+        freq_radius: usize,
+        /// How many times louder than the neighbourhood mean a peak has to be.
+        threshold: f32,
+        /// Number of peaks kept per second of audio, the most prominent ones first.
+        peaks_per_second: usize,
+    },
+}
+
+impl PeakStrategy {
+    /// Constellation picking with defaults suited to the default frame size.
+    pub fn constellation() -> Self {
+        Self::Constellation {
+            time_radius: 5,
+            freq_radius: 10,
+            threshold: 2.0,
+            peaks_per_second: 30,
+        }
+    }
+}
+
+/// Pairing strategy of the fingerprint pipeline.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PairingStrategy {
+    /// Nearest peaks in (ms, Hz) space on either side of the anchor.
+    #[default]
+    Nearest,
+    /// Loudest peaks inside a zone ahead of the anchor, hashed as
+    /// (anchor bin, target bin, frame delta).
+    TargetZone {
+        /// Frames between the anchor and the start of the zone.
+        delay: usize,
+        /// Length of the zone in frames.
+        length: usize,
+        /// Bins the zone extends above and below the anchor.
+        height: usize,
+    },
+    /// Triplets of an anchor and two peaks of the same zone as
+    /// [`PairingStrategy::TargetZone`], hashed as frequency and time ratios so
+    /// clips played a few percent faster or slower still match.
+    Triplets {
+        delay: usize,
+        length: usize,
+        height: usize,
+    },
+}
+
+impl PairingStrategy {
+    /// Target zone pairing with defaults suited to the default frame size.
+    pub fn target_zone() -> Self {
+        Self::TargetZone {
+            delay: 1,
+            length: 20,
+            height: 64,
+        }
+    }
+
+    /// Triplet pairing over the default target zone.
+    pub fn triplets() -> Self {
+        Self::Triplets {
+            delay: 1,
+            length: 20,
+            height: 64,
+        }
+    }
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        Self {
+            version: PIPELINE_VERSION,
+            fft_size: NUM_BINS,
+            hop_size: NUM_BINS / 2,
+            bands: BANDS.to_vec(),
+            fan_out: ANCHOR_POINTS - 1,
+            peak_threshold: 1.0,
+            target_sample_rate: DEFAULT_SAMPLE_RATE / 2,
+            peak_strategy: PeakStrategy::BandMax,
+            pairing_strategy: PairingStrategy::Nearest,
+            frequency_scale: FrequencyScale::Linear,
+        }
+    }
+}
+
+impl FingerprintConfig {
+    /// Whether hashes computed with `other` can be matched against hashes computed with `self`.
+    pub fn is_compatible(&self, other: &FingerprintConfig) -> bool {
+        self == other
+    }
+
+    /// Layout of the hashes produced with this config.
+    pub fn hash_version(&self) -> u8 {
+        match self.pairing_strategy {
+            PairingStrategy::Triplets { .. } => RATIO_HASH_VERSION,
+            _ => HASH_VERSION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Fingerprint {
+    /// `(hash, time)` pairs in time order, with the time in milliseconds. A hash
+    /// appears once for every place it occurs in the song.
+    pub hashes: Vec<(u64, u64)>,
+    /// Layout of the hashes, see [`crate::hash`].
+    pub hash_version: u8,
+    pub decode: DecodeReport,
+}
+
+impl Fingerprint {
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+/// Fingerprints an encoded audio file held in memory, used by every query path.
+pub fn fingerprint_bytes(bytes: Vec<u8>, config: &FingerprintConfig) -> Result<Fingerprint> {
+    fingerprint_decoder(MonoDecoder::new(open_binary(bytes)?)?, config)
+}
+
+pub fn fingerprint_file(path: impl AsRef<Path>, config: &FingerprintConfig) -> Result<Fingerprint> {
+    fingerprint_decoder(MonoDecoder::new(open_file(path)?)?, config)
+}
+
+/// Fingerprints the rest of the audio of `decoder`.
+pub(crate) fn fingerprint_decoder(
+    mut decoder: MonoDecoder,
+    config: &FingerprintConfig,
+) -> Result<Fingerprint> {
+    let mut fingerprinter = Fingerprinter::new(decoder.sample_rate(), config);
+    let mut hashes = vec![];
+    while let Some(samples) = decoder.next_chunk()? {
+        hashes.extend(fingerprinter.push(samples));
+    }
+    hashes.extend(fingerprinter.finish());
+    let decode = decoder.finish()?;
+    Ok(Fingerprint {
+        hashes,
+        hash_version: config.hash_version(),
+        decode,
+    })
+}
+
+/// Incremental fingerprinter fed with decoded mono samples in chunks.
+///
+/// Only the samples of the current analysis frame and the peaks inside the
+/// pairing window are kept, so memory does not grow with the length of the input.
+/// Hashes are emitted as soon as every peak their anchor can pair with is known.
+pub struct Fingerprinter {
+    config: FingerprintConfig,
+    resampler: Option<Resampler>,
+    /// Analysis sample rate after resampling.
+    sr: u32,
+    /// Resampled samples not yet consumed by a full frame.
+    pending: Vec<f32>,
+    /// Samples still to drop when the hop is longer than the frame.
+    skip: usize,
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    picker: PeakPicker,
+    /// Peaks in time order, from the oldest one still inside the window of an unpaired anchor.
+    peaks: VecDeque<(usize, usize, f32)>,
+    /// Index in `peaks` of the first anchor not paired yet.
+    next_anchor: usize,
+}
+
+impl Fingerprinter {
+    /// Creates a fingerprinter for input at `sample_rate`.
+    pub fn new(sample_rate: u32, config: &FingerprintConfig) -> Self {
+        let resampler = (sample_rate != config.target_sample_rate)
+            .then(|| Resampler::new(sample_rate, config.target_sample_rate));
+        let sr = resampler
+            .as_ref()
+            .map_or(sample_rate, |r| r.output_rate(sample_rate));
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(config.fft_size);
+        let window = (0..config.fft_size)
+            .map(|n| hann_function(n, config.fft_size))
+            .collect();
+
+        Self {
+            config: config.clone(),
+            resampler,
+            sr,
+            pending: vec![],
+            skip: 0,
+            fft,
+            window,
+            picker: PeakPicker::new(sr, config),
+            peaks: VecDeque::new(),
+            next_anchor: 0,
+        }
+    }
+
+    /// Feeds the next chunk of samples, returns the `(hash, time)` pairs completed by it.
+    pub fn push(&mut self, samples: &[f32]) -> Vec<(u64, u64)> {
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                let resampled = resampler.process(samples);
+                self.analyse(&resampled);
+            }
+            None => self.analyse(samples),
+        }
+        self.pair(false)
+    }
+
+    /// Flushes the remaining input and pairs every peak left.
+    pub fn finish(mut self) -> Vec<(u64, u64)> {
+        if let Some(resampler) = self.resampler.as_mut() {
+            let resampled = resampler.flush();
+            self.analyse(&resampled);
+        }
+        let peaks = self.picker.finish();
+        self.peaks.extend(peaks);
+        self.pair(true)
+    }
+
+    /// Turns every complete frame of the resampled samples into peaks.
+    fn analyse(&mut self, samples: &[f32]) {
+        let (fft_size, hop_size) = (self.config.fft_size, self.config.hop_size);
+        let skipped = self.skip.min(samples.len());
+        self.skip -= skipped;
+        self.pending.extend_from_slice(&samples[skipped..]);
+
+        let mut spectrum = self.fft.make_output_vec();
+        while self.pending.len() >= fft_size {
+            // every frame is normalised by its own loudest sample, so a clip is analysed
+            // exactly like the same stretch of the whole track
+            let peak = self.pending[..fft_size]
+                .iter()
+                .fold(0.0f32, |peak, &x| peak.max(x.abs()));
+            let scale = if peak > 0.0 { 1.0 / peak } else { 0.0 };
+            let mut frame = self.pending[..fft_size]
+                .iter()
+                .zip(&self.window)
+                .map(|(&x, &w)| x * w * scale)
+                .collect::<Vec<f32>>();
+            self.fft.process(&mut frame, &mut spectrum).unwrap();
+            let magnitudes = spectrum.iter().map(|c| c.norm()).collect::<Vec<f32>>();
+            let peaks = self.picker.push(magnitudes);
+            self.peaks.extend(peaks);
+
+            let drained = hop_size.min(self.pending.len());
+            self.pending.drain(..drained);
+            self.skip = hop_size - drained;
+        }
+    }
+
+    /// Pairs every anchor whose window is complete, or all of them at the end of the input.
+    fn pair(&mut self, last: bool) -> Vec<(u64, u64)> {
+        let now = self.picker.horizon();
+        let (behind, ahead) = self.pairing_window();
+        let mut hashes = vec![];
+        while let Some(&anchor) = self.peaks.get(self.next_anchor) {
+            let (time, ..) = anchor;
+            if !last && time + ahead >= now {
+                break;
+            }
+            let anchor_hashes = match self.config.pairing_strategy {
+                PairingStrategy::Nearest => {
+                    let window = self
+                        .peaks
+                        .iter()
+                        .enumerate()
+                        .filter(|&(i, &(t, ..))| i != self.next_anchor && t.abs_diff(time) <= ahead)
+                        .map(|(_, peak)| peak);
+                    nearest_hashes(&anchor, window, self.sr as usize, &self.config)
+                }
+                PairingStrategy::TargetZone {
+                    delay,
+                    length,
+                    height,
+                } => {
+                    let later = self.peaks.range(self.next_anchor + 1..);
+                    target_zone_hashes(
+                        &anchor,
+                        later,
+                        (delay, length, height),
+                        self.sr as usize,
+                        &self.config,
+                    )
+                }
+                PairingStrategy::Triplets {
+                    delay,
+                    length,
+                    height,
+                } => {
+                    let later = self.peaks.range(self.next_anchor + 1..);
+                    triplet_hashes(
+                        &anchor,
+                        later,
+                        (delay, length, height),
+                        self.sr as usize,
+                        &self.config,
+                    )
+                }
+            };
+            // an anchor can reach two peaks with the same bin and delta
+            let mut anchor_hashes = anchor_hashes;
+            anchor_hashes.sort_unstable();
+            anchor_hashes.dedup();
+            hashes.extend(anchor_hashes.into_iter().map(|hash| (hash, time as u64)));
+            self.next_anchor += 1;
+
+            // peaks before the window of the next anchor are never read again
+            let start = self
+                .peaks
+                .get(self.next_anchor)
+                .map_or(time, |&(t, ..)| t)
+                .saturating_sub(behind);
+            while self.peaks.front().is_some_and(|&(t, ..)| t < start) {
+                self.peaks.pop_front();
+                self.next_anchor -= 1;
+            }
+        }
+        hashes
+    }
+
+    /// Milliseconds before and after an anchor its pairs can be found in.
+    fn pairing_window(&self) -> (usize, usize) {
+        match self.config.pairing_strategy {
+            PairingStrategy::Nearest => (PAIRING_WINDOW_MS, PAIRING_WINDOW_MS),
+            PairingStrategy::TargetZone { delay, length, .. }
+            | PairingStrategy::Triplets { delay, length, .. } => (
+                0,
+                frame_time(delay + length, self.sr as usize, self.config.hop_size) + 1,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// 16-bit WAV of `samples` in `-1.0..1.0`.
+    fn wav(samples: impl IntoIterator<Item = f32>) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(vec![]);
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for x in samples {
+            writer.write_sample((x * 16000.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        wav.into_inner()
+    }
+
+    /// Five seconds of 16-bit WAV hopping between two tones every 100 ms.
+    pub(crate) fn melody() -> Vec<u8> {
+        tones(7, 0, 5)
+    }
+
+    /// Seconds `start..start + secs` of the tones [`melody`] hops between, picked
+    /// from `seed`.
+    pub(crate) fn tones(seed: u32, start: u32, secs: u32) -> Vec<u8> {
+        let mut seed = seed;
+        let mut pair = (0.0, 0.0);
+        let samples = (0..(start + secs) * SAMPLE_RATE).map(|n| {
+            if n % (SAMPLE_RATE / 10) == 0 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                pair = (
+                    200.0 + (seed >> 16) as f32 % 1800.0,
+                    2000.0 + (seed >> 8) as f32 % 3000.0,
+                );
+            }
+            let t = n as f32 / SAMPLE_RATE as f32;
+            0.5 * (2.0 * std::f32::consts::PI * pair.0 * t).sin()
+                + 0.3 * (2.0 * std::f32::consts::PI * pair.1 * t).sin()
+        });
+        wav(samples.skip((start * SAMPLE_RATE) as usize))
+    }
+
+    /// `secs` of 16-bit WAV of white noise from `seed`.
+    pub(crate) fn noise(seed: u32, secs: u32) -> Vec<u8> {
+        let mut seed = seed;
+        wav((0..secs * SAMPLE_RATE).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as f32 / 65536.0 - 0.5
+        }))
+    }
+
+    #[test]
+    fn fingerprints_generated_signal() {
+        let config = FingerprintConfig::default();
+        let wav = melody();
+        let fingerprint = fingerprint_bytes(wav.clone(), &config).unwrap();
+        assert!(fingerprint.len() > 100);
+        assert_eq!(fingerprint.hash_version, config.hash_version());
+        assert!(fingerprint.hashes.is_sorted_by_key(|&(_, time)| time));
+        assert!(fingerprint.hashes.iter().all(|&(_, time)| time < 5000));
+
+        let again = fingerprint_bytes(wav.clone(), &config).unwrap();
+        assert_eq!(again.hashes, fingerprint.hashes);
+
+        let path = std::env::temp_dir().join(format!("fingerprint-{}.wav", std::process::id()));
+        std::fs::write(&path, &wav).unwrap();
+        let from_file = fingerprint_file(&path, &config);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_file.unwrap().hashes, fingerprint.hashes);
+    }
+
+    #[test]
+    fn chunk_size_does_not_change_hashes() {
+        let config = FingerprintConfig::default();
+        let fingerprint = fingerprint_bytes(melody(), &config).unwrap();
+        let (samples, sr, _) = extract_mono_audio(open_binary(melody()).unwrap()).unwrap();
+        let mut fingerprinter = Fingerprinter::new(sr, &config);
+        let mut hashes = vec![];
+        for chunk in samples.chunks(1000) {
+            hashes.extend(fingerprinter.push(chunk));
+        }
+        hashes.extend(fingerprinter.finish());
+        assert_eq!(hashes, fingerprint.hashes);
+    }
+
+    #[test]
+    fn clip_peaks_match_the_same_part_of_the_track() {
+        let config = FingerprintConfig::default();
+        let sr = config.target_sample_rate;
+        // fading out, so a running peak would scale a clip from the middle differently
+        let samples = (0..5 * sr)
+            .map(|n| {
+                let t = n as f32 / sr as f32;
+                let fade = 1.0 - 0.15 * t;
+                fade * (0.6 * (2.0 * std::f32::consts::PI * (300.0 + 200.0 * t) * t).sin()
+                    - 0.3 * (2.0 * std::f32::consts::PI * 2500.0 * t).sin())
+            })
+            .collect::<Vec<f32>>();
+        let peaks = |samples: &[f32]| {
+            let mut fingerprinter = Fingerprinter::new(sr, &config);
+            fingerprinter.analyse(samples);
+            fingerprinter.peaks.into_iter().collect::<Vec<_>>()
+        };
+        let frame_of = |time| {
+            (0..)
+                .find(|&i| frame_time(i, sr as usize, config.hop_size) == time)
+                .unwrap()
+        };
+
+        let (offset, frames) = (40, 50);
+        let start = offset * config.hop_size;
+        let clip = peaks(&samples[start..start + (frames - 1) * config.hop_size + config.fft_size]);
+        let expected = peaks(&samples)
+            .into_iter()
+            .filter(|&(time, ..)| (offset..offset + frames).contains(&frame_of(time)))
+            .map(|(time, freq, magnitude)| {
+                let time = frame_time(frame_of(time) - offset, sr as usize, config.hop_size);
+                (time, freq, magnitude)
+            })
+            .collect::<Vec<_>>();
+        assert!(!clip.is_empty());
+        assert_eq!(clip, expected);
+    }
+}
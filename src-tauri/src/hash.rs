@@ -0,0 +1,222 @@
+//! Layouts of the 64 bit fingerprint hashes, the top 8 bits always hold the
+//! layout version.
+//!
+//! Pair hashes, version [`HASH_VERSION`]:
+//!
+//! | bits     | width | field                                    |
+//! |----------|-------|------------------------------------------|
+//! | `63..56` | 8     | layout version                           |
+//! | `55..40` | 16    | FFT bin of the anchor peak               |
+//! | `39..24` | 16    | FFT bin of the paired peak               |
+//! | `23..0`  | 24    | time between the two peaks in frames     |
+//!
+//! Ratio hashes of a peak triplet, version [`RATIO_HASH_VERSION`], unchanged
+//! when the audio is played faster or slower:
+//!
+//! | bits     | width | field                                                   |
+//! |----------|-------|---------------------------------------------------------|
+//! | `63..56` | 8     | layout version                                          |
+//! | `55..44` | 12    | `log2(f_b / f_a)` in [`RATIO_STEPS_PER_OCTAVE`] steps   |
+//! | `43..32` | 12    | `log2(f_c / f_a)` in [`RATIO_STEPS_PER_OCTAVE`] steps   |
+//! | `31..24` | 8     | `(t_b - t_a) / (t_c - t_a)` in 256 steps                |
+//! | `23..0`  | 24    | zero                                                    |
+//!
+//! Fields larger than their width saturate at its maximum, the signed octave
+//! fields are stored with an offset of half their range. The version lives in
+//! the hash itself so hashes of different layouts never collide, every song
+//! also records the version it was stored with.
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the pair layout, bumped whenever a field changes.
+pub const HASH_VERSION: u8 = 1;
+/// Version of the ratio layout, bumped whenever a field changes.
+pub const RATIO_HASH_VERSION: u8 = 2;
+
+pub const VERSION_BITS: u32 = 8;
+pub const BIN_BITS: u32 = 16;
+pub const DELTA_BITS: u32 = 24;
+pub const RATIO_BITS: u32 = 12;
+pub const TIME_RATIO_BITS: u32 = 8;
+
+/// Resolution of the quantised frequency ratios.
+pub const RATIO_STEPS_PER_OCTAVE: f32 = 32.0;
+
+const DELTA_SHIFT: u32 = 0;
+const TARGET_SHIFT: u32 = DELTA_SHIFT + DELTA_BITS;
+const ANCHOR_SHIFT: u32 = TARGET_SHIFT + BIN_BITS;
+const VERSION_SHIFT: u32 = ANCHOR_SHIFT + BIN_BITS;
+
+const TIME_RATIO_SHIFT: u32 = 24;
+const SECOND_RATIO_SHIFT: u32 = TIME_RATIO_SHIFT + TIME_RATIO_BITS;
+const FIRST_RATIO_SHIFT: u32 = SECOND_RATIO_SHIFT + RATIO_BITS;
+
+/// Fields of a hash, as returned by [`decode_hash`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "layout", rename_all = "camelCase")]
+pub enum HashFields {
+    #[serde(rename_all = "camelCase")]
+    Pair {
+        version: u8,
+        anchor_bin: u32,
+        target_bin: u32,
+        delta_frames: u32,
+    },
+    /// Frequency ratios in octaves and the time ratio in `0.0..1.0`, as quantised.
+    #[serde(rename_all = "camelCase")]
+    Ratio {
+        version: u8,
+        first_octaves: f32,
+        second_octaves: f32,
+        time_ratio: f32,
+    },
+}
+
+/// Packs an anchor bin, a paired bin and their distance in frames into a pair hash.
+pub fn encode_hash(anchor_bin: usize, target_bin: usize, delta_frames: usize) -> u64 {
+    ((HASH_VERSION as u64) << VERSION_SHIFT)
+        | (saturate(anchor_bin, BIN_BITS) << ANCHOR_SHIFT)
+        | (saturate(target_bin, BIN_BITS) << TARGET_SHIFT)
+        | (saturate(delta_frames, DELTA_BITS) << DELTA_SHIFT)
+}
+
+/// Packs a triplet of an anchor and two later peaks `b` and `c` into a ratio hash,
+/// from their frequencies and their distances to the anchor with `delta_b < delta_c`.
+pub fn encode_ratio_hash(
+    anchor_freq: usize,
+    (freq_b, freq_c): (usize, usize),
+    (delta_b, delta_c): (usize, usize),
+) -> u64 {
+    let octaves = |freq: usize| {
+        let steps = ((freq as f32 / anchor_freq as f32).log2() * RATIO_STEPS_PER_OCTAVE).round();
+        saturate_signed(steps as i64, RATIO_BITS)
+    };
+    let time_ratio = (delta_b as f32 / delta_c as f32 * (1 << TIME_RATIO_BITS) as f32) as usize;
+    ((RATIO_HASH_VERSION as u64) << VERSION_SHIFT)
+        | (octaves(freq_b) << FIRST_RATIO_SHIFT)
+        | (octaves(freq_c) << SECOND_RATIO_SHIFT)
+        | (saturate(time_ratio, TIME_RATIO_BITS) << TIME_RATIO_SHIFT)
+}
+
+/// Splits a hash back into its fields, for debugging.
+pub fn decode_hash(hash: u64) -> HashFields {
+    let version = field(hash, VERSION_SHIFT, VERSION_BITS) as u8;
+    if version == RATIO_HASH_VERSION {
+        let octaves = |shift: u32| {
+            (field(hash, shift, RATIO_BITS) as i64 - (1 << (RATIO_BITS - 1))) as f32
+                / RATIO_STEPS_PER_OCTAVE
+        };
+        return HashFields::Ratio {
+            version,
+            first_octaves: octaves(FIRST_RATIO_SHIFT),
+            second_octaves: octaves(SECOND_RATIO_SHIFT),
+            time_ratio: field(hash, TIME_RATIO_SHIFT, TIME_RATIO_BITS) as f32
+                / (1 << TIME_RATIO_BITS) as f32,
+        };
+    }
+    HashFields::Pair {
+        version,
+        anchor_bin: field(hash, ANCHOR_SHIFT, BIN_BITS),
+        target_bin: field(hash, TARGET_SHIFT, BIN_BITS),
+        delta_frames: field(hash, DELTA_SHIFT, DELTA_BITS),
+    }
+}
+
+fn saturate(value: usize, bits: u32) -> u64 {
+    (value as u64).min((1 << bits) - 1)
+}
+
+/// Offsets a signed value by half the range of the field before saturating it.
+fn saturate_signed(value: i64, bits: u32) -> u64 {
+    let half = 1i64 << (bits - 1);
+    (value + half).clamp(0, 2 * half - 1) as u64
+}
+
+fn field(hash: u64, shift: u32, bits: u32) -> u32 {
+    ((hash >> shift) & ((1 << bits) - 1)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_hash_round_trip() {
+        let hash = encode_hash(123, 4567, 89);
+        assert_eq!(
+            decode_hash(hash),
+            HashFields::Pair {
+                version: HASH_VERSION,
+                anchor_bin: 123,
+                target_bin: 4567,
+                delta_frames: 89,
+            }
+        );
+        assert_eq!((hash >> VERSION_SHIFT) as u8, HASH_VERSION);
+    }
+
+    #[test]
+    fn ratio_hash_round_trip() {
+        // an octave above and a third of an octave below, a quarter of the way along
+        let hash = encode_ratio_hash(1000, (2000, 800), (25, 100));
+        let HashFields::Ratio {
+            version,
+            first_octaves,
+            second_octaves,
+            time_ratio,
+        } = decode_hash(hash)
+        else {
+            panic!("expected a ratio hash");
+        };
+        assert_eq!(version, RATIO_HASH_VERSION);
+        assert_eq!((hash >> VERSION_SHIFT) as u8, RATIO_HASH_VERSION);
+        let step = 1.0 / RATIO_STEPS_PER_OCTAVE;
+        assert!((first_octaves - 1.0).abs() <= step / 2.0);
+        assert!((second_octaves - 0.8f32.log2()).abs() <= step / 2.0);
+        assert_eq!(time_ratio, 0.25);
+    }
+
+    #[test]
+    fn fields_saturate_instead_of_spilling() {
+        let max = |bits: u32| (1u32 << bits) - 1;
+        let hash = encode_hash(usize::MAX, 1 << BIN_BITS, 1 << DELTA_BITS);
+        assert_eq!(
+            decode_hash(hash),
+            HashFields::Pair {
+                version: HASH_VERSION,
+                anchor_bin: max(BIN_BITS),
+                target_bin: max(BIN_BITS),
+                delta_frames: max(DELTA_BITS),
+            }
+        );
+        // a bin one past the field must not change the version or its neighbour
+        let hash = encode_hash(0, 1 << BIN_BITS, 0);
+        assert_eq!(
+            decode_hash(hash),
+            HashFields::Pair {
+                version: HASH_VERSION,
+                anchor_bin: 0,
+                target_bin: max(BIN_BITS),
+                delta_frames: 0,
+            }
+        );
+
+        // ratios far outside the field clamp to its ends, the time ratio below one
+        let hash = encode_ratio_hash(1, (usize::MAX, 0), (10, 10));
+        let HashFields::Ratio {
+            version,
+            first_octaves,
+            second_octaves,
+            time_ratio,
+        } = decode_hash(hash)
+        else {
+            panic!("expected a ratio hash");
+        };
+        assert_eq!(version, RATIO_HASH_VERSION);
+        let half = (1 << (RATIO_BITS - 1)) as f32;
+        assert_eq!(first_octaves, (half - 1.0) / RATIO_STEPS_PER_OCTAVE);
+        assert_eq!(second_octaves, -half / RATIO_STEPS_PER_OCTAVE);
+        assert_eq!(time_ratio, max(TIME_RATIO_BITS) as f32 / 256.0);
+        assert_eq!(hash & ((1 << TIME_RATIO_SHIFT) - 1), 0);
+    }
+}
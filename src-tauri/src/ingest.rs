@@ -0,0 +1,179 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc::unbounded_channel, task::spawn_blocking};
+
+use crate::fingerprint::{Fingerprint, FingerprintConfig, fingerprint_decoder};
+use crate::matcher::{MatchResult, MatcherConfig, find_match};
+use crate::metadata::{Cover, EmbeddedMetadata, probe};
+use crate::schema::TrackResult;
+use crate::store::{FingerprintStore, ensure_config};
+use crate::utils::{MonoDecoder, open_binary};
+
+/// What to do with a song whose recording is already in the database under another id.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicatePolicy {
+    /// Leave the database unchanged and report the existing song.
+    Reject,
+    /// Link the new id to the existing song instead of storing it again.
+    #[default]
+    Alias,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum IngestResult {
+    Added,
+    /// The recording is already stored as `existing_id`, the song was not added.
+    #[serde(rename_all = "camelCase")]
+    Duplicate {
+        existing_id: String,
+        confidence: f32,
+    },
+    /// The recording is already stored as `existing_id`, the new id now resolves to it.
+    #[serde(rename_all = "camelCase")]
+    Aliased {
+        existing_id: String,
+        confidence: f32,
+    },
+}
+
+/// Steps of [`ingest_song`], reported as they start.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum IngestStep {
+    /// Probing the format, reading the embedded tags and opening the decoder.
+    Decoding,
+    /// Decoding the audio and hashing its spectrogram peaks, which stream together.
+    Fingerprinting,
+    /// Checking for duplicates and writing the hashes.
+    Storing,
+}
+
+/// Fingerprints an encoded song and stores it, unless the database already holds
+/// the same recording under another id, in which case `policy` decides. Fields
+/// `track` is missing are filled from the tags embedded in the audio, an
+/// embedded cover is stored with the song when `track` has no image.
+///
+/// Decoding and fingerprinting run on the blocking thread pool, which reports
+/// its steps back through a channel.
+pub async fn ingest_song(
+    store: &dyn FingerprintStore,
+    config: &FingerprintConfig,
+    matcher_config: &MatcherConfig,
+    policy: DuplicatePolicy,
+    audio: Vec<u8>,
+    track: TrackResult,
+    on_step: impl Fn(IngestStep),
+) -> Result<IngestResult> {
+    ensure_config(store, config).await?;
+
+    let (steps, mut started) = unbounded_channel();
+    let config = config.clone();
+    let task = spawn_blocking(move || {
+        let _ = steps.send(IngestStep::Decoding);
+        // the tags and the audio are read from the same probe, the buffer is not copied
+        let mut probe = probe(open_binary(audio)?, None)?;
+        let metadata = EmbeddedMetadata::from_probe(&mut probe);
+        let decoder = MonoDecoder::from_format(probe.format)?;
+        let _ = steps.send(IngestStep::Fingerprinting);
+        let fingerprint = fingerprint_decoder(decoder, &config)?;
+        anyhow::Ok((metadata, fingerprint))
+    });
+    // ends once the task returns and drops the sender
+    while let Some(step) = started.recv().await {
+        on_step(step);
+    }
+    let (metadata, fingerprint) = task.await??;
+    let mut track = track;
+    metadata.fill(&mut track);
+    let cover = metadata.into_cover(&track);
+
+    on_step(IngestStep::Storing);
+    ingest_fingerprint(store, matcher_config, policy, fingerprint, track, cover).await
+}
+
+/// Stores a song fingerprinted with the database config, see [`ingest_song`].
+pub async fn ingest_fingerprint(
+    store: &dyn FingerprintStore,
+    matcher_config: &MatcherConfig,
+    policy: DuplicatePolicy,
+    fingerprint: Fingerprint,
+    track: TrackResult,
+    cover: Option<Cover>,
+) -> Result<IngestResult> {
+    // the whole song is the query, so the same recording lines up almost entirely
+    let duplicate_config = MatcherConfig {
+        min_confidence: matcher_config.duplicate_confidence,
+        ..matcher_config.clone()
+    };
+    if let MatchResult::Match {
+        track: existing,
+        confidence,
+        ..
+    } = find_match(store, &fingerprint, &duplicate_config).await?
+        && existing.id != track.id
+    {
+        let existing_id = existing.id;
+        return Ok(match policy {
+            DuplicatePolicy::Reject => IngestResult::Duplicate {
+                existing_id,
+                confidence,
+            },
+            DuplicatePolicy::Alias => {
+                store.add_alias(&track.id, &existing_id).await?;
+                IngestResult::Aliased {
+                    existing_id,
+                    confidence,
+                }
+            }
+        });
+    }
+
+    store
+        .insert_song(
+            fingerprint.hashes,
+            track,
+            fingerprint.hash_version,
+            cover.as_ref(),
+        )
+        .await?;
+    Ok(IngestResult::Added)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::fingerprint::tests::melody;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn reports_every_step_in_order() {
+        let store = MemoryStore::default();
+        let track = EmbeddedMetadata::default().to_track("a".into(), "a".into(), String::new());
+        let steps = Mutex::new(vec![]);
+        let result = ingest_song(
+            &store,
+            &FingerprintConfig::default(),
+            &MatcherConfig::default(),
+            DuplicatePolicy::Alias,
+            melody(),
+            track,
+            |step| steps.lock().unwrap().push(step),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(result, IngestResult::Added));
+        assert_eq!(
+            steps.into_inner().unwrap(),
+            vec![
+                IngestStep::Decoding,
+                IngestStep::Fingerprinting,
+                IngestStep::Storing
+            ]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::{sync::Semaphore, task::AbortHandle};
+
+use crate::AppState;
+use crate::ingest::{IngestResult, IngestStep, ingest_song};
+use crate::music_finder::*;
+use crate::schema::TrackResult;
+
+/// Event emitted with the [`JobInfo`] of a job whenever its stage changes.
+pub const JOB_EVENT: &str = "ingest-job";
+
+/// Jobs running at once, the others wait in [`JobStage::Queued`].
+const MAX_RUNNING_JOBS: usize = 2;
+/// Finished jobs kept for [`JobManager::list`] and retries, the oldest ones are dropped first.
+const MAX_FINISHED_JOBS: usize = 50;
+
+/// Song to download and ingest.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "source", rename_all = "camelCase")]
+pub enum JobRequest {
+    Jiosaavn { track: Box<TrackResult> },
+    Youtube { url: String },
+}
+
+impl JobRequest {
+    fn label(&self) -> String {
+        match self {
+            JobRequest::Jiosaavn { track } => track.name.clone(),
+            JobRequest::Youtube { url } => url.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "stage", rename_all = "camelCase")]
+pub enum JobStage {
+    Queued,
+    /// Share of the download done in `0.0..=1.0`, `None` while the size is unknown.
+    Downloading {
+        progress: Option<f32>,
+    },
+    Decoding,
+    Fingerprinting,
+    Storing,
+    Done {
+        result: IngestResult,
+    },
+    Failed {
+        error: String,
+    },
+    Cancelled,
+}
+
+impl JobStage {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStage::Done { .. } | JobStage::Failed { .. } | JobStage::Cancelled
+        )
+    }
+}
+
+impl From<IngestStep> for JobStage {
+    fn from(step: IngestStep) -> Self {
+        match step {
+            IngestStep::Decoding => JobStage::Decoding,
+            IngestStep::Fingerprinting => JobStage::Fingerprinting,
+            IngestStep::Storing => JobStage::Storing,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    pub id: u64,
+    pub label: String,
+    pub request: JobRequest,
+    #[serde(flatten)]
+    pub stage: JobStage,
+}
+
+struct Job {
+    info: JobInfo,
+    /// Bumped on every retry, updates from an earlier run are ignored.
+    attempt: u32,
+    handle: Option<AbortHandle>,
+}
+
+/// Runs ingest jobs in the background, so commands return as soon as a job is queued.
+///
+/// Cancelling aborts a job at its next await point. Every store writes a song,
+/// cover included, in a single transaction or blocking call, so a cancelled job
+/// never leaves part of a song behind.
+pub struct JobManager {
+    app: AppHandle,
+    jobs: Arc<Mutex<BTreeMap<u64, Job>>>,
+    next_id: AtomicU64,
+    slots: Arc<Semaphore>,
+}
+
+impl JobManager {
+    pub fn new(app: AppHandle) -> Self {
+        Self {
+            app,
+            jobs: Arc::default(),
+            next_id: AtomicU64::new(1),
+            slots: Arc::new(Semaphore::new(MAX_RUNNING_JOBS)),
+        }
+    }
+
+    /// Queues a job, returns its id.
+    pub fn enqueue(&self, request: JobRequest) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = JobInfo {
+            id,
+            label: request.label(),
+            request,
+            stage: JobStage::Queued,
+        };
+        {
+            let mut jobs = self.lock()?;
+            prune(&mut jobs);
+            jobs.insert(
+                id,
+                Job {
+                    info: info.clone(),
+                    attempt: 0,
+                    handle: None,
+                },
+            );
+        }
+        self.start(info, 0);
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Result<Vec<JobInfo>> {
+        Ok(self.lock()?.values().map(|job| job.info.clone()).collect())
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<()> {
+        let info = {
+            let mut jobs = self.lock()?;
+            let job = jobs.get_mut(&id).ok_or(anyhow!("No job with id {}", id))?;
+            if job.info.stage.is_finished() {
+                return Err(anyhow!("Job {} has already finished", id));
+            }
+            if let Some(handle) = job.handle.take() {
+                handle.abort();
+            }
+            job.info.stage = JobStage::Cancelled;
+            job.info.clone()
+        };
+        self.app.emit(JOB_EVENT, &info)?;
+        Ok(())
+    }
+
+    /// Runs a failed or cancelled job again under the same id.
+    pub fn retry(&self, id: u64) -> Result<()> {
+        let (info, attempt) = {
+            let mut jobs = self.lock()?;
+            let job = jobs.get_mut(&id).ok_or(anyhow!("No job with id {}", id))?;
+            if !matches!(
+                job.info.stage,
+                JobStage::Failed { .. } | JobStage::Cancelled
+            ) {
+                return Err(anyhow!("Job {} has not failed or been cancelled", id));
+            }
+            job.attempt += 1;
+            job.info.stage = JobStage::Queued;
+            (job.info.clone(), job.attempt)
+        };
+        self.start(info, attempt);
+        Ok(())
+    }
+
+    /// Spawns the run of a queued job, which goes ahead even if the queued event
+    /// cannot be emitted since the job list reports it all the same.
+    fn start(&self, info: JobInfo, attempt: u32) {
+        // emitted before the run can report a later stage
+        let _ = self.app.emit(JOB_EVENT, &info);
+        let progress = Progress {
+            app: self.app.clone(),
+            jobs: self.jobs.clone(),
+            id: info.id,
+            attempt,
+        };
+        let failed = progress.clone();
+        let slots = self.slots.clone();
+        let task = tauri::async_runtime::spawn(async move {
+            let Ok(_slot) = slots.acquire_owned().await else {
+                return;
+            };
+            let stage = match run(&progress, info.request).await {
+                Ok(result) => JobStage::Done { result },
+                Err(e) => JobStage::Failed {
+                    error: format!("{:#}", e),
+                },
+            };
+            progress.set(stage);
+        });
+        let handle = task.inner().abort_handle();
+        // a panic ends the run before it sets a stage, so it is reported from here
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = task.await {
+                failed.set(JobStage::Failed {
+                    error: format!("{:#}", e),
+                });
+            }
+        });
+        if let Ok(mut jobs) = self.lock()
+            && let Some(job) = jobs.get_mut(&info.id)
+        {
+            job.handle = Some(handle);
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<u64, Job>>> {
+        self.jobs.lock().map_err(|e| anyhow!("{}", e))
+    }
+}
+
+/// Drops the oldest finished jobs beyond [`MAX_FINISHED_JOBS`].
+fn prune(jobs: &mut BTreeMap<u64, Job>) {
+    let finished = jobs
+        .iter()
+        .filter(|(_, job)| job.info.stage.is_finished())
+        .map(|(&id, _)| id)
+        .collect::<Vec<u64>>();
+    for id in &finished[..finished.len().saturating_sub(MAX_FINISHED_JOBS)] {
+        jobs.remove(id);
+    }
+}
+
+/// Handle a running job reports its stage through.
+#[derive(Clone)]
+struct Progress {
+    app: AppHandle,
+    jobs: Arc<Mutex<BTreeMap<u64, Job>>>,
+    id: u64,
+    attempt: u32,
+}
+
+impl Progress {
+    fn set(&self, stage: JobStage) {
+        let info = {
+            let Ok(mut jobs) = self.jobs.lock() else {
+                return;
+            };
+            let Some(job) = jobs.get_mut(&self.id) else {
+                return;
+            };
+            // a cancelled or retried run may still be finishing its current step, and an
+            // aborted one ends with an error that is not a failure
+            if job.attempt != self.attempt || matches!(job.info.stage, JobStage::Cancelled) {
+                return;
+            }
+            job.info.stage = stage;
+            job.info.clone()
+        };
+        let _ = self.app.emit(JOB_EVENT, &info);
+    }
+
+    /// Download callback, emitting only when the progress moves by a whole percent.
+    fn download(&self) -> impl FnMut(u64, Option<u64>) + '_ {
+        let mut last = None;
+        move |read, total| {
+            let progress = total.map(|total| read as f32 / total.max(1) as f32);
+            let percent = progress.map(|p| (p * 100.0) as u32);
+            if last != Some(percent) {
+                last = Some(percent);
+                self.set(JobStage::Downloading { progress });
+            }
+        }
+    }
+}
+
+async fn run(progress: &Progress, request: JobRequest) -> Result<IngestResult> {
+    let state = progress.app.state::<AppState>();
+    // checked before downloading, so a job fails fast and can be retried once it is back
+    let storage = state.storage.get()?;
+    let services = state.services();
+    let (client, settings) = (&services.req_client, &services.settings);
+    progress.set(JobStage::Downloading { progress: None });
+    let (music, track) = match request {
+        JobRequest::Jiosaavn { track } => {
+            let music =
+                download_jiosaavn_music(client, settings, (*track).clone(), progress.download())
+                    .await?;
+            (music, *track)
+        }
+        JobRequest::Youtube { url } => {
+            let track = get_youtube_music_data(client, settings, &url)
+                .await?
+                .to_track();
+            let music = download_youtube_music(client, settings, &url, progress.download()).await?;
+            (music, track)
+        }
+    };
+    ingest_song(
+        storage.store.as_ref(),
+        &storage.fingerprint_config,
+        &settings.matcher,
+        settings.duplicate_policy,
+        music,
+        track,
+        |step| progress.set(step.into()),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, stage: JobStage) -> Job {
+        Job {
+            info: JobInfo {
+                id,
+                label: id.to_string(),
+                request: JobRequest::Youtube { url: String::new() },
+                stage,
+            },
+            attempt: 0,
+            handle: None,
+        }
+    }
+
+    #[test]
+    fn prune_keeps_recent_and_unfinished_jobs() {
+        let mut jobs = (1..=MAX_FINISHED_JOBS as u64 + 10)
+            .map(|id| {
+                let stage = if id % 10 == 0 {
+                    JobStage::Queued
+                } else {
+                    JobStage::Cancelled
+                };
+                (id, job(id, stage))
+            })
+            .collect::<BTreeMap<u64, Job>>();
+        prune(&mut jobs);
+
+        let finished = jobs
+            .values()
+            .filter(|job| job.info.stage.is_finished())
+            .count();
+        assert_eq!(finished, MAX_FINISHED_JOBS);
+        // the queued ones survive however old they are
+        assert!(jobs.contains_key(&10));
+        assert!(!jobs.contains_key(&1));
+        assert!(jobs.contains_key(&(MAX_FINISHED_JOBS as u64 + 9)));
+    }
+}
@@ -1,41 +1,111 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 
+pub mod chromaprint;
 mod db;
+pub mod fingerprint;
+pub mod hash;
+mod ingest;
+mod jobs;
+mod library;
+mod matcher;
+mod metadata;
 mod music_finder;
+mod peaks;
+mod resample;
+mod retry;
 mod schema;
+mod settings;
+mod storage;
+mod store;
 mod utils;
 
 // use anyhow::Result;
-use crate::db::*;
+use crate::fingerprint::*;
+use crate::ingest::*;
+use crate::jobs::*;
+use crate::library::*;
+use crate::matcher::*;
 use crate::music_finder::*;
 use crate::schema::*;
-use crate::utils::*;
-use anyhow::Result;
-use redis::{AsyncCommands, aio::ConnectionManager};
+use crate::settings::*;
+use crate::storage::*;
+use crate::store::*;
 use regex::Regex;
-use tauri::{State, async_runtime::Runtime};
-use tokio::runtime::Runtime as TokioRuntime;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock};
+use tauri::{Manager, State};
 
 struct AppState {
-    pub redis_client: ConnectionManager,
+    pub services: RwLock<Services>,
+    pub storage: StorageManager,
+    pub data_dir: PathBuf,
+}
+
+impl AppState {
+    /// The current services, calls keep using the ones they started with
+    /// when the settings change under them.
+    fn services(&self) -> Services {
+        self.services
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Everything built from the [`Settings`] besides the store, replaced
+/// together when they change.
+#[derive(Clone)]
+struct Services {
+    pub settings: Settings,
     pub req_client: reqwest::Client,
 }
 
+/// Error of the commands that need the store, so the frontend can tell
+/// "not connected yet" apart from a failed call.
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", content = "message", rename_all = "camelCase")]
+enum CommandError {
+    StorageUnavailable(String),
+    Failed(String),
+}
+
+impl From<StorageUnavailable> for CommandError {
+    fn from(e: StorageUnavailable) -> Self {
+        CommandError::StorageUnavailable(e.to_string())
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(e: String) -> Self {
+        CommandError::Failed(e)
+    }
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let redis_client = Runtime::Tokio(TokioRuntime::new().unwrap())
-        .block_on(connect_redis())
-        .expect("Redis Connection Error");
-    let req_client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .build()
-        .expect("Client Build Error");
-
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(AppState {
-            redis_client: redis_client.into(),
-            req_client,
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir()?;
+            let (settings, problems) = Settings::load(&data_dir);
+            // the app starts without the store, it connects in the background
+            let storage = StorageManager::new(app.handle().clone());
+            if !problems.is_empty() {
+                storage.set_settings_warning(Some(problems.join(", ")));
+            }
+            storage.connect(settings.clone(), data_dir.clone());
+            let services = Services {
+                req_client: settings.http_client()?,
+                settings,
+            };
+            app.manage(AppState {
+                services: RwLock::new(services),
+                storage,
+                data_dir,
+            });
+            app.manage(JobManager::new(app.handle().clone()));
+            Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             greet,
@@ -45,20 +115,32 @@ pub fn run() {
             add_music_to_db_command,
             add_youtube_music_to_db_command,
             delete_song_command,
+            get_cover_command,
+            migrate_reverse_index_command,
             similar_songs_command,
             check_if_song_exists_command,
+            chromaprint_command,
+            ingest_folder_command,
+            list_jobs_command,
+            cancel_job_command,
+            retry_job_command,
+            get_settings_command,
+            validate_settings_command,
+            update_settings_command,
+            connection_status_command,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
 
 #[tauri::command]
-async fn ping_redis_command(state: State<'_, AppState>) -> Result<(), String> {
-    let mut client = state.redis_client.clone();
-    client
-        .ping::<()>()
+async fn ping_redis_command(state: State<'_, AppState>) -> Result<(), CommandError> {
+    let storage = state.storage.get()?;
+    Ok(storage
+        .store
+        .ping()
         .await
-        .map_err(|e| format!("Redis Ping Error: {:?}", e))
+        .map_err(|e| format!("Redis Ping Error: {:?}", e))?)
 }
 
 #[tauri::command]
@@ -67,32 +149,70 @@ fn greet(name: &str) -> String {
 }
 
 #[tauri::command]
-async fn get_all_songs_command(state: State<'_, AppState>) -> Result<Vec<TrackResult>, String> {
-    let mut client = state.redis_client.clone();
+async fn get_all_songs_command(
+    state: State<'_, AppState>,
+) -> Result<Vec<TrackResult>, CommandError> {
+    let storage = state.storage.get()?;
+    Ok(storage
+        .store
+        .get_all_tracks()
+        .await
+        .map_err(|e| format!("Redis Get All Songs Error: {:?}", e))?)
+}
 
-    get_all_songs(&mut client)
+#[tauri::command]
+async fn delete_song_command(id: &str, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let storage = state.storage.get()?;
+    Ok(storage
+        .store
+        .delete_song(id)
         .await
-        .map_err(|e| format!("Redis Get All Songs Error: {:?}", e))
+        .map_err(|e| format!("Redis Delete Song Error: {:?}", e))?)
 }
 
+/// Stored cover of the song `id` as a `data:` URL, for tracks whose image URL is a
+/// [`metadata::COVER_URL_PREFIX`] reference.
 #[tauri::command]
-async fn delete_song_command(id: &str, state: State<'_, AppState>) -> Result<(), String> {
-    let mut client = state.redis_client.clone();
-    delete_song(&mut client, id)
+async fn get_cover_command(
+    id: &str,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, CommandError> {
+    let storage = state.storage.get()?;
+    let cover = storage
+        .store
+        .get_cover(id)
         .await
-        .map_err(|e| format!("Redis Delete Song Error: {:?}", e))
+        .map_err(|e| format!("Get Cover Error: {:?}", e))?;
+    Ok(cover.map(|cover| cover.to_data_url()))
+}
+
+#[tauri::command]
+async fn migrate_reverse_index_command(state: State<'_, AppState>) -> Result<usize, CommandError> {
+    let storage = state.storage.get()?;
+    Ok(storage
+        .store
+        .rebuild_index()
+        .await
+        .map_err(|e| format!("Redis Migrate Reverse Index Error: {:?}", e))?)
 }
 
 #[tauri::command]
 async fn check_if_song_exists_command(
     id: &str,
     state: State<'_, AppState>,
-) -> Result<bool, String> {
-    let mut client = state.redis_client.clone();
-    let exists = get_redis_json(&mut client, &format!("song:{}", id))
+) -> Result<bool, CommandError> {
+    let store = state.storage.get()?.store;
+    let exists = store
+        .get_track(id)
+        .await
+        .map_err(|e| format!("Redis Get Song Error: {:?}", e))?
+        .is_some();
+    let aliased = store
+        .resolve_alias(id)
         .await
-        .is_ok();
-    Ok(exists)
+        .map_err(|e| format!("Redis Get Alias Error: {:?}", e))?
+        .is_some();
+    Ok(exists || aliased)
 }
 
 #[tauri::command]
@@ -100,6 +220,8 @@ async fn get_song_from_url_command(
     url: &str,
     state: State<'_, AppState>,
 ) -> Result<Vec<TrackResult>, String> {
+    let services = state.services();
+    let (client, settings) = (&services.req_client, &services.settings);
     let (y_reg, sp_reg, j_reg) = (
         Regex::new(r"youtu\.?be").map_err(|e| format!("Regex Error: {:?}", e))?,
         Regex::new(r"spotify").map_err(|e| format!("Regex Error: {:?}", e))?,
@@ -110,13 +232,13 @@ async fn get_song_from_url_command(
         sp_reg.is_match(url),
         j_reg.is_match(url),
     ) {
-        (true, _, _) => find_youtube_music(&state.req_client, url)
+        (true, _, _) => find_youtube_music(client, settings, url)
             .await
             .map_err(|e| format!("Youtube Music Error: {:?}", e)),
-        (_, true, _) => find_spotify_music(&state.req_client, url)
+        (_, true, _) => find_spotify_music(client, settings, url)
             .await
             .map_err(|e| format!("Spotify Music Error: {:?}", e)),
-        (_, _, true) => find_jiosaavn_music(&state.req_client, url)
+        (_, _, true) => find_jiosaavn_music(client, settings, url)
             .await
             .map_err(|e| format!("Jiosaavn Music Error: {:?}", e)),
         _ => Err("Invalid URL".to_string()),
@@ -124,87 +246,138 @@ async fn get_song_from_url_command(
 }
 
 #[tauri::command]
-async fn add_youtube_music_to_db_command(
-    url: &str,
+fn add_youtube_music_to_db_command(url: &str, jobs: State<'_, JobManager>) -> Result<u64, String> {
+    jobs.enqueue(JobRequest::Youtube {
+        url: url.to_string(),
+    })
+    .map_err(|e| format!("Enqueue Job Error: {:?}", e))
+}
+
+#[tauri::command]
+fn add_music_to_db_command(val: TrackResult, jobs: State<'_, JobManager>) -> Result<u64, String> {
+    jobs.enqueue(JobRequest::Jiosaavn {
+        track: Box::new(val),
+    })
+    .map_err(|e| format!("Enqueue Job Error: {:?}", e))
+}
+
+#[tauri::command]
+fn list_jobs_command(jobs: State<'_, JobManager>) -> Result<Vec<JobInfo>, String> {
+    jobs.list().map_err(|e| format!("List Jobs Error: {:?}", e))
+}
+
+#[tauri::command]
+fn cancel_job_command(id: u64, jobs: State<'_, JobManager>) -> Result<(), String> {
+    jobs.cancel(id)
+        .map_err(|e| format!("Cancel Job Error: {:?}", e))
+}
+
+#[tauri::command]
+fn retry_job_command(id: u64, jobs: State<'_, JobManager>) -> Result<(), String> {
+    jobs.retry(id)
+        .map_err(|e| format!("Retry Job Error: {:?}", e))
+}
+
+#[tauri::command]
+async fn ingest_folder_command(
+    path: &str,
     state: State<'_, AppState>,
-) -> Result<(), String> {
-    let mut redis_client = state.redis_client.clone();
-    let music_data = get_youtube_music_data(&state.req_client, url)
+) -> Result<Vec<FileReport>, CommandError> {
+    let storage = state.storage.get()?;
+    let settings = state.services().settings;
+    Ok(ingest_folder(
+        storage.store.as_ref(),
+        &storage.fingerprint_config,
+        &settings.matcher,
+        settings.duplicate_policy,
+        path,
+    )
+    .await
+    .map_err(|e| format!("Ingest Folder Error: {:?}", e))?)
+}
+
+#[tauri::command]
+async fn similar_songs_command(
+    audio: Vec<u8>,
+    state: State<'_, AppState>,
+) -> Result<MatchResult, CommandError> {
+    let storage = state.storage.get()?;
+    let config = storage.fingerprint_config.clone();
+    check_config(storage.store.as_ref(), &config)
         .await
-        .map_err(|e| format!("Youtube Music Data Error: {:?}", e))?
-        .to_track();
-    let music = download_youtube_music(&state.req_client, url)
+        .map_err(|e| format!("Fingerprint Config Error: {:?}", e))?;
+    // decoding and the FFT would hold up the async runtime
+    let fingerprint = tokio::task::spawn_blocking(move || fingerprint_bytes(audio, &config))
         .await
-        .map_err(|e| format!("Youtube Music Error: {:?}", e))?;
-    let id = music_data.id.clone();
-    let mss = open_binary(music).map_err(|e| format!("Open Binary Error: {:?}", e))?;
-    let (audio, sr) =
-        extract_mono_audio(mss).map_err(|e| format!("Extract Mono Audio Error: {:?}", e))?;
-    let (audio, _sr) = downsample(audio, sr, 2);
-    let audio = normalise(audio);
-    let stft = stft(audio, NUM_BINS, NUM_BINS / 2).map_err(|e| format!("STFT Error: {:?}", e))?;
-    let filtered_stft = filter_stft(stft, sr as usize, NUM_BINS, NUM_BINS / 2);
-    let data =
-        filter_to_data(filtered_stft, &id).map_err(|e| format!("Filter To Data Error: {:?}", e))?;
-    let mut songs = Vec::with_capacity(data.len());
-    for (id, data) in data.into_iter() {
-        songs.push((id, data.0, data.1));
-    }
-    set_all_songs(&mut redis_client, songs, music_data)
+        .map_err(anyhow::Error::from)
+        .and_then(|fingerprint| fingerprint)
+        .map_err(|e| format!("Fingerprint Error: {:?}", e))?;
+    let matcher_config = state.services().settings.matcher;
+    let result = find_match(storage.store.as_ref(), &fingerprint, &matcher_config)
         .await
-        .map_err(|e| format!("Redis Set All Songs Error: {:?}", e))?;
-    Ok(())
+        .map_err(|e| format!("Find Match Error: {:?}", e))?;
+    Ok(result)
+}
+
+#[tauri::command]
+async fn chromaprint_command(audio: Vec<u8>) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || chromaprint::chromaprint_bytes(audio))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|fingerprint| fingerprint)
+        .map_err(|e| format!("Chromaprint Error: {:?}", e))
+}
+
+#[tauri::command]
+fn get_settings_command(state: State<'_, AppState>) -> Settings {
+    state.services().settings
 }
 
 #[tauri::command]
-async fn add_music_to_db_command(
-    val: TrackResult,
+fn validate_settings_command(settings: Settings) -> Vec<SettingsIssue> {
+    settings.validate()
+}
+
+/// Applies and saves `settings`, reconnecting the store in the background when
+/// its location changed.
+#[tauri::command]
+async fn update_settings_command(
+    settings: Settings,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
-    let mut redis_client = state.redis_client.clone();
-
-    let id = val.id.clone();
-    let music = download_jiosaavn_music(&state.req_client, val.clone())
-        .await
-        .map_err(|e| format!("Download Jiosaavn Music Error: {:?}", e))?;
-    let mss = open_binary(music).map_err(|e| format!("Open Binary Error: {:?}", e))?;
-    let (audio, sr) =
-        extract_mono_audio(mss).map_err(|e| format!("Extract Mono Audio Error: {:?}", e))?;
-    let (audio, _sr) = downsample(audio, sr, 2);
-    let audio = normalise(audio);
-    let stft = stft(audio, NUM_BINS, NUM_BINS / 2).map_err(|e| format!("STFT Error: {:?}", e))?;
-    let filtered_stft = filter_stft(stft, sr as usize, NUM_BINS, NUM_BINS / 2);
-    let data =
-        filter_to_data(filtered_stft, &id).map_err(|e| format!("Filter To Data Error: {:?}", e))?;
-    let mut songs = Vec::with_capacity(data.len());
-    for (id, data) in data.into_iter() {
-        songs.push((id, data.0, data.1));
+    let issues = settings.validate();
+    if !issues.is_empty() {
+        let messages = issues
+            .into_iter()
+            .map(|issue| issue.message)
+            .collect::<Vec<String>>();
+        return Err(format!("Invalid Settings: {}", messages.join(", ")));
     }
-    set_all_songs(&mut redis_client, songs, val)
-        .await
-        .map_err(|e| format!("Redis Set All Songs Error: {:?}", e))?;
+    let services = Services {
+        req_client: settings
+            .http_client()
+            .map_err(|e| format!("Client Build Error: {:?}", e))?,
+        settings,
+    };
+    services
+        .settings
+        .save(&state.data_dir)
+        .map_err(|e| format!("Save Settings Error: {:?}", e))?;
+    state.storage.set_settings_warning(None);
+    let store_changed = services.settings.store_changed(&state.services().settings);
+    if store_changed {
+        state
+            .storage
+            .connect(services.settings.clone(), state.data_dir.clone());
+    }
+    *state
+        .services
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = services;
     Ok(())
 }
 
 #[tauri::command]
-async fn similar_songs_command(
-    audio: Vec<u8>,
-    state: State<'_, AppState>,
-) -> Result<Vec<TrackResult>, String> {
-    let mut redis_client = state.redis_client.clone();
-
-    let mss = crate::open_binary(audio).map_err(|e| format!("Open Binary Error: {:?}", e))?;
-    let (audio, sr) =
-        crate::extract_mono_audio(mss).map_err(|e| format!("Extract Mono Audio Error: {:?}", e))?;
-    let (audio, _sr) = crate::downsample(audio, sr, 2);
-    let audio = crate::normalise(audio);
-    let stft = crate::stft(audio, crate::NUM_BINS, crate::NUM_BINS / 2)
-        .map_err(|e| format!("STFT Error: {:?}", e))?;
-    let filtered_stft = crate::filter_stft(stft, sr as usize, crate::NUM_BINS, crate::NUM_BINS / 2);
-    let data = crate::filter_to_data(filtered_stft, "tmp")
-        .map_err(|e| format!("Filter To Data Error: {:?}", e))?;
-    let similar_songs = get_similar_songs(&mut redis_client, data)
-        .await
-        .map_err(|e| format!("Get Similar Songs Error: {:?}", e))?;
-    Ok(similar_songs)
+fn connection_status_command(state: State<'_, AppState>) -> StorageStatus {
+    state.storage.status()
 }
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::fingerprint::{Fingerprint, FingerprintConfig, fingerprint_file};
+use crate::ingest::{DuplicatePolicy, IngestResult, ingest_fingerprint};
+use crate::matcher::MatcherConfig;
+use crate::metadata::{Cover, EmbeddedMetadata, read_metadata};
+use crate::schema::TrackResult;
+use crate::store::{FingerprintStore, ensure_config};
+use crate::utils::open_file;
+
+/// File extensions of the formats symphonia can decode.
+const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "flac", "wav", "ogg", "oga", "m4a", "mp4", "aac"];
+
+/// Outcome of ingesting one file of a folder.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileReport {
+    pub path: String,
+    #[serde(flatten)]
+    pub outcome: FileOutcome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "outcome", rename_all = "camelCase")]
+pub enum FileOutcome {
+    Ingested { id: String, result: IngestResult },
+    Failed { error: String },
+}
+
+/// Fingerprints every audio file under `dir` in parallel and stores them, a
+/// file or folder that cannot be read or decoded is reported without stopping the others.
+///
+/// Files are processed a few per thread at a time, so only that many
+/// fingerprints are held in memory however large the folder is.
+pub async fn ingest_folder(
+    store: &dyn FingerprintStore,
+    config: &FingerprintConfig,
+    matcher_config: &MatcherConfig,
+    policy: DuplicatePolicy,
+    dir: impl AsRef<Path>,
+) -> Result<Vec<FileReport>> {
+    ensure_config(store, config).await?;
+    let dir = dir.as_ref().to_path_buf();
+    let (files, mut reports) = audio_files(&dir)?;
+
+    reports.reserve(files.len());
+    for batch in files.chunks(rayon::current_num_threads() * 2) {
+        let batch = batch.to_vec();
+        let (config, dir) = (config.clone(), dir.clone());
+        let fingerprinted = tokio::task::spawn_blocking(move || {
+            batch
+                .into_par_iter()
+                .map(|path| {
+                    let result = fingerprint_local_file(&path, &dir, &config);
+                    (path, result)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?;
+
+        // stored one at a time so duplicates within the folder are caught too
+        for (path, result) in fingerprinted {
+            let outcome = match result {
+                Ok((fingerprint, track, cover)) => {
+                    let id = track.id.clone();
+                    match ingest_fingerprint(
+                        store,
+                        matcher_config,
+                        policy,
+                        fingerprint,
+                        track,
+                        cover,
+                    )
+                    .await
+                    {
+                        Ok(result) => FileOutcome::Ingested { id, result },
+                        Err(e) => FileOutcome::Failed {
+                            error: format!("{:#}", e),
+                        },
+                    }
+                }
+                Err(e) => FileOutcome::Failed {
+                    error: format!("{:#}", e),
+                },
+            };
+            reports.push(FileReport {
+                path: path.to_string_lossy().into_owned(),
+                outcome,
+            });
+        }
+    }
+    Ok(reports)
+}
+
+/// Every file under `dir` with an audio extension in path order, with a failure
+/// report for every folder or entry that could not be read. Only `dir` itself
+/// not being readable is an error.
+///
+/// Symbolic links to files are followed, a file reached more than once is only
+/// listed once. Links to folders are reported and skipped so a link cycle cannot
+/// make the walk endless.
+fn audio_files(dir: &Path) -> Result<(Vec<PathBuf>, Vec<FileReport>)> {
+    let (mut files, mut failures) = (vec![], vec![]);
+    let failed = |path: &Path, error: String| FileReport {
+        path: path.to_string_lossy().into_owned(),
+        outcome: FileOutcome::Failed { error },
+    };
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = match std::fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(e) if current == dir => return Err(e.into()),
+            Err(e) => {
+                failures.push(failed(&current, format!("Failed to read folder: {}", e)));
+                continue;
+            }
+        };
+        for entry in entries {
+            let (path, file_type) =
+                match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        failures.push(failed(&current, format!("Failed to read entry: {}", e)));
+                        continue;
+                    }
+                };
+            let file_type = if file_type.is_symlink() {
+                match std::fs::metadata(&path) {
+                    Ok(target) if target.is_dir() => {
+                        failures.push(failed(&path, "Linked folder not followed".to_string()));
+                        continue;
+                    }
+                    Ok(target) => target.file_type(),
+                    Err(e) => {
+                        failures.push(failed(&path, format!("Broken link: {}", e)));
+                        continue;
+                    }
+                }
+            } else {
+                file_type
+            };
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    // a link and its target have the same id, only the first in path order is kept
+    let mut targets = HashSet::new();
+    files.retain(|path| targets.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((files, failures))
+}
+
+fn fingerprint_local_file(
+    path: &Path,
+    dir: &Path,
+    config: &FingerprintConfig,
+) -> Result<(Fingerprint, TrackResult, Option<Cover>)> {
+    let fingerprint = fingerprint_file(path, config)?;
+    let (mut track, cover) = local_track(path, dir)?;
+    // the container duration is missing or approximate for some formats
+    track.duration = Some(fingerprint.decode.duration_secs);
+    Ok((fingerprint, track, cover))
+}
+
+/// Builds the metadata of a local file from its embedded tags, falling back to
+/// the `Artist/Album/Title.ext` layout for files at least two folders below `dir`,
+/// with the embedded cover it references.
+fn local_track(path: &Path, dir: &Path) -> Result<(TrackResult, Option<Cover>)> {
+    let component = |path: Option<&Path>| {
+        path.and_then(|p| p.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+    };
+    // the layout is read from the walked path, a link may point outside of `dir`
+    let album_dir = path.parent().filter(|_| {
+        path.strip_prefix(dir)
+            .is_ok_and(|relative| relative.components().count() >= 3)
+    });
+    let fallback = EmbeddedMetadata {
+        album: component(album_dir),
+        artists: component(album_dir.and_then(Path::parent))
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+
+    let path = path.canonicalize()?;
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    let metadata = read_metadata(open_file(&path)?, extension)?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .ok_or(anyhow!("No file name: {}", path.display()))?;
+    let url = format!("file://{}", path.display());
+
+    let mut track = metadata.to_track(local_id(&path), name, url);
+    fallback.fill(&mut track);
+    let cover = metadata.into_cover(&track);
+    Ok((track, cover))
+}
+
+/// Stable id of a local file from its path, FNV-1a so it does not change between builds.
+fn local_id(path: &Path) -> String {
+    let hash = path
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("local-{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Fresh folder under the temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// Writes a short silent WAV without tags at `relative`.
+        fn wav(&self, relative: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: 8000,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for _ in 0..800 {
+                writer.write_sample(0i16).unwrap();
+            }
+            writer.finalize().unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn layout_only_below_two_folders() {
+        let dir = TempDir::new("library-layout");
+        let track = |relative: &str| local_track(&dir.wav(relative), &dir.0).unwrap().0;
+
+        let nested = track("Artist/Album/Title.wav");
+        assert_eq!(nested.name, "Title");
+        assert_eq!(nested.album.name.as_deref(), Some("Album"));
+        assert_eq!(nested.artists.primary[0].name, "Artist");
+
+        for relative in ["Title.wav", "Album/Title.wav"] {
+            let track = track(relative);
+            assert_eq!(track.name, "Title");
+            assert_eq!(track.album.name, None, "{relative}");
+            assert!(track.artists.primary.is_empty(), "{relative}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_reports_links_instead_of_following_folders() {
+        let dir = TempDir::new("library-walk");
+        let song = dir.wav("Artist/Album/Song.mp3");
+        dir.wav("Artist/cover.jpg");
+        std::os::unix::fs::symlink(&dir.0, dir.0.join("Artist/loop")).unwrap();
+        std::os::unix::fs::symlink(&song, dir.0.join("Linked.flac")).unwrap();
+        std::os::unix::fs::symlink(dir.0.join("missing"), dir.0.join("Broken.ogg")).unwrap();
+
+        let (files, failures) = audio_files(&dir.0).unwrap();
+        assert_eq!(files, vec![song]);
+        let failed = failures
+            .iter()
+            .map(|report| report.path.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            failed,
+            ["Artist/loop", "Broken.ogg"].map(|relative| dir
+                .0
+                .join(relative)
+                .to_string_lossy()
+                .into_owned())
+        );
+
+        assert!(audio_files(&dir.0.join("missing")).is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::fingerprint::Fingerprint;
+use crate::hash::RATIO_HASH_VERSION;
+use crate::schema::TrackResult;
+use crate::store::FingerprintStore;
+
+/// Width of the offset histogram bins in milliseconds.
+pub const OFFSET_BIN_MS: i64 = 50;
+
+/// Step between the speed factors tried for speed-invariant hashes.
+const SPEED_STEP: f64 = 0.005;
+
+/// Songs with the most hits that get the speed search, the others cannot outrank them.
+const SPEED_CANDIDATES: usize = 20;
+
+/// Thresholds the best candidate has to pass to be reported as a match.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MatcherConfig {
+    /// Minimum share of the query hashes that must agree on one offset.
+    pub min_confidence: f32,
+    /// Minimum number of aligned hashes, guards against very short clips.
+    pub min_aligned_hashes: usize,
+    /// Hashes occurring more often than this within one song do not vote for it,
+    /// they stand for sounds too common to place the query.
+    pub max_occurrences: usize,
+    /// Largest relative speed change searched for when the query was fingerprinted
+    /// with speed-invariant hashes, `0.1` tries speeds from 90% to 110%.
+    pub max_speed_change: f64,
+    /// Minimum confidence at which a song being ingested counts as a recording
+    /// already in the database, matched with its whole fingerprint.
+    pub duplicate_confidence: f32,
+}
+
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        Self {
+            min_confidence: 0.02,
+            min_aligned_hashes: 10,
+            max_occurrences: 8,
+            max_speed_change: 0.1,
+            duplicate_confidence: 0.1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum MatchResult {
+    #[serde(rename_all = "camelCase")]
+    Match {
+        track: Box<TrackResult>,
+        /// Share of the query hashes that line up on `offset_secs`, in `0.0..=1.0`.
+        confidence: f32,
+        aligned_hashes: usize,
+        /// Position of the start of the query clip inside the track in seconds.
+        offset_secs: f64,
+        /// Playback speed of the query relative to the track, `1.1` when it plays 10% faster.
+        /// Always `1.0` unless the query used speed-invariant hashes.
+        speed: f64,
+    },
+    NoMatch,
+}
+
+struct Candidate {
+    song_id: String,
+    aligned_hashes: usize,
+    offset_ms: i64,
+    speed: f64,
+}
+
+/// Speed factors within `max_change` of `1.0`, closest to `1.0` first.
+fn speed_factors(max_change: f64) -> Vec<f64> {
+    let steps = (max_change / SPEED_STEP).round() as usize;
+    let mut speeds = vec![1.0];
+    for step in 1..=steps {
+        let change = step as f64 * SPEED_STEP;
+        speeds.extend([1.0 - change, 1.0 + change]);
+    }
+    speeds
+}
+
+/// Ranks songs by the tallest bin of their `db_time - speed * query_time` histogram
+/// over the given `speeds`, hashes of the true song line up on one offset at the
+/// speed the query was played at while random hits spread out.
+///
+/// `query_times` holds every time a hash occurs in the query. Each occurrence
+/// votes at most once per song and offset, so a hash repeated in the song
+/// supports every offset it occurs at without inflating any of them.
+fn rank_candidates(
+    query_times: Vec<Vec<u64>>,
+    all_members: Vec<Vec<(u64, String)>>,
+    max_occurrences: usize,
+    speeds: &[f64],
+) -> Vec<Candidate> {
+    // (query occurrence, query time, db time) of every hit, per song
+    let mut hits: HashMap<String, Vec<(usize, u64, u64)>> = HashMap::new();
+    let mut occurrence = 0;
+    for (times, members) in query_times.into_iter().zip(all_members) {
+        let mut occurrences: HashMap<&str, usize> = HashMap::new();
+        for (_, song_id) in &members {
+            *occurrences.entry(song_id).or_insert(0) += 1;
+        }
+        for query_time in times {
+            for (time, song_id) in &members {
+                if occurrences[song_id.as_str()] <= max_occurrences {
+                    hits.entry(song_id.clone())
+                        .or_default()
+                        .push((occurrence, query_time, *time));
+                }
+            }
+            occurrence += 1;
+        }
+    }
+
+    let mut songs = hits.into_iter().collect::<Vec<_>>();
+    if speeds.len() > 1 {
+        songs.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+        songs.truncate(SPEED_CANDIDATES);
+    }
+
+    let mut candidates = songs
+        .into_iter()
+        .filter_map(|(song_id, hits)| {
+            let mut best: Option<Candidate> = None;
+            for &speed in speeds {
+                let votes = hits
+                    .iter()
+                    .map(|&(occurrence, query_time, time)| {
+                        let offset = time as f64 - speed * query_time as f64;
+                        (occurrence, (offset as i64).div_euclid(OFFSET_BIN_MS))
+                    })
+                    .collect::<HashSet<_>>();
+                let mut histogram: HashMap<i64, usize> = HashMap::new();
+                for (_, bin) in votes {
+                    *histogram.entry(bin).or_insert(0) += 1;
+                }
+                let Some((bin, aligned_hashes)) = histogram
+                    .into_iter()
+                    .max_by_key(|&(bin, count)| (count, -bin))
+                else {
+                    continue;
+                };
+                // on a tie the speed closest to 1.0, tried first, wins
+                if best
+                    .as_ref()
+                    .is_none_or(|best| aligned_hashes > best.aligned_hashes)
+                {
+                    best = Some(Candidate {
+                        song_id: song_id.clone(),
+                        aligned_hashes,
+                        offset_ms: bin * OFFSET_BIN_MS,
+                        speed,
+                    });
+                }
+            }
+            best
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| {
+        b.aligned_hashes
+            .cmp(&a.aligned_hashes)
+            .then_with(|| a.song_id.cmp(&b.song_id))
+    });
+    candidates
+}
+
+pub async fn find_match(
+    store: &dyn FingerprintStore,
+    fingerprint: &Fingerprint,
+    config: &MatcherConfig,
+) -> Result<MatchResult> {
+    let query_hashes = fingerprint.len();
+    // repeated hashes are looked up once
+    let mut times_by_hash: HashMap<u64, Vec<u64>> = HashMap::new();
+    for &(hash, time) in &fingerprint.hashes {
+        times_by_hash.entry(hash).or_default().push(time);
+    }
+    let (hashes, query_times): (Vec<u64>, Vec<Vec<u64>>) = times_by_hash.into_iter().unzip();
+    let all_members = store.lookup_hashes(&hashes).await?;
+
+    let confidence =
+        |aligned_hashes: usize| (aligned_hashes as f32 / query_hashes.max(1) as f32).min(1.0);
+    let speeds = if fingerprint.hash_version == RATIO_HASH_VERSION {
+        speed_factors(config.max_speed_change)
+    } else {
+        vec![1.0]
+    };
+    let candidates = rank_candidates(query_times, all_members, config.max_occurrences, &speeds)
+        .into_iter()
+        .take_while(|candidate| {
+            confidence(candidate.aligned_hashes) >= config.min_confidence
+                && candidate.aligned_hashes >= config.min_aligned_hashes
+        })
+        .collect::<Vec<_>>();
+
+    // hashes of songs stored with another layout can only line up by accident
+    let ids = candidates
+        .iter()
+        .map(|candidate| candidate.song_id.clone())
+        .collect::<Vec<_>>();
+    let versions = store.get_hash_versions(&ids).await?;
+    let Some(best) = candidates
+        .into_iter()
+        .zip(versions)
+        .find(|(_, version)| *version == Some(fingerprint.hash_version))
+        .map(|(candidate, _)| candidate)
+    else {
+        return Ok(MatchResult::NoMatch);
+    };
+    let confidence = confidence(best.aligned_hashes);
+
+    let Some(track) = store.get_track(&best.song_id).await? else {
+        return Ok(MatchResult::NoMatch);
+    };
+    Ok(MatchResult::Match {
+        track: Box::new(track),
+        confidence,
+        aligned_hashes: best.aligned_hashes,
+        offset_secs: best.offset_ms as f64 / 1000.0,
+        speed: best.speed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::tests::{noise, tones};
+    use crate::fingerprint::{FingerprintConfig, fingerprint_bytes};
+    use crate::hash::{HASH_VERSION, encode_ratio_hash};
+    use crate::metadata::EmbeddedMetadata;
+    use crate::store::MemoryStore;
+
+    /// Reports every candidate, so the tests see the ranking itself.
+    fn any_match() -> MatcherConfig {
+        MatcherConfig {
+            min_confidence: 0.0,
+            min_aligned_hashes: 1,
+            ..MatcherConfig::default()
+        }
+    }
+
+    fn fingerprint(hashes: Vec<(u64, u64)>, hash_version: u8) -> Fingerprint {
+        Fingerprint {
+            hashes,
+            hash_version,
+            decode: Default::default(),
+        }
+    }
+
+    async fn store(songs: Vec<(&str, Vec<(u64, u64)>)>, hash_version: u8) -> MemoryStore {
+        let store = MemoryStore::default();
+        for (id, hashes) in songs {
+            let track =
+                EmbeddedMetadata::default().to_track(id.to_string(), id.to_string(), String::new());
+            store
+                .insert_song(hashes, track, hash_version, None)
+                .await
+                .unwrap();
+        }
+        store
+    }
+
+    /// Id, aligned hashes, offset in seconds and speed of a match.
+    fn matched(result: MatchResult) -> (String, usize, f64, f64) {
+        match result {
+            MatchResult::Match {
+                track,
+                aligned_hashes,
+                offset_secs,
+                speed,
+                ..
+            } => (track.id, aligned_hashes, offset_secs, speed),
+            MatchResult::NoMatch => panic!("expected a match"),
+        }
+    }
+
+    #[tokio::test]
+    async fn aligned_hits_outrank_more_scattered_ones() {
+        let query = (1..=20).map(|i| (i, i * 100)).collect::<Vec<_>>();
+        let store = store(
+            vec![
+                // every hash of the query, each at another offset
+                (
+                    "scattered",
+                    (1..=20).map(|i| (i, i * 100 + i * i * 60)).collect(),
+                ),
+                // half of them, all 5 s into the song
+                ("aligned", (1..=10).map(|i| (i, i * 100 + 5000)).collect()),
+            ],
+            HASH_VERSION,
+        )
+        .await;
+
+        let result = find_match(&store, &fingerprint(query, HASH_VERSION), &any_match())
+            .await
+            .unwrap();
+        let (id, aligned_hashes, offset_secs, _) = matched(result);
+        assert_eq!(id, "aligned");
+        assert_eq!(aligned_hashes, 10);
+        assert_eq!(offset_secs, 5.0);
+    }
+
+    #[tokio::test]
+    async fn finds_clip_at_its_offset() {
+        let config = FingerprintConfig::default();
+        let song = fingerprint_bytes(tones(7, 0, 5), &config).unwrap();
+        let store = store(vec![("song", song.hashes)], config.hash_version()).await;
+
+        let clip = fingerprint_bytes(tones(7, 2, 2), &config).unwrap();
+        let result = find_match(&store, &clip, &MatcherConfig::default())
+            .await
+            .unwrap();
+        let (id, _, offset_secs, speed) = matched(result);
+        assert_eq!(id, "song");
+        // within a frame and an offset bin of where the clip starts
+        assert!((offset_secs - 2.0).abs() < 0.1, "offset {}", offset_secs);
+        assert_eq!(speed, 1.0);
+    }
+
+    #[tokio::test]
+    async fn unrelated_audio_is_no_match() {
+        let config = FingerprintConfig::default();
+        let song = fingerprint_bytes(tones(7, 0, 5), &config).unwrap();
+        let store = store(vec![("song", song.hashes)], config.hash_version()).await;
+
+        let other = fingerprint_bytes(noise(7, 3), &config).unwrap();
+        assert!(!other.is_empty());
+        let result = find_match(&store, &other, &MatcherConfig::default())
+            .await
+            .unwrap();
+        assert!(matches!(result, MatchResult::NoMatch));
+    }
+
+    #[tokio::test]
+    async fn weak_matches_are_rejected() {
+        let config = MatcherConfig::default();
+        let store = store(
+            vec![("song", (1..=100).map(|i| (i, i * 100)).collect())],
+            HASH_VERSION,
+        )
+        .await;
+        let find = |hashes: Vec<(u64, u64)>, config: MatcherConfig| {
+            let store = &store;
+            async move {
+                find_match(store, &fingerprint(hashes, HASH_VERSION), &config)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // fewer aligned hashes than required, however confident
+        let few = (1..config.min_aligned_hashes as u64)
+            .map(|i| (i, i * 100))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            find(few, config.clone()).await,
+            MatchResult::NoMatch
+        ));
+
+        // 20 aligned among 2000 query hashes is a confidence of 1%
+        let diluted = (1..=2000)
+            .map(|i| (if i > 20 { i + 1000 } else { i }, i * 100))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            find(diluted.clone(), config.clone()).await,
+            MatchResult::NoMatch
+        ));
+        let lenient = MatcherConfig {
+            min_confidence: 0.005,
+            ..config
+        };
+        let (_, aligned_hashes, offset_secs, _) = matched(find(diluted, lenient).await);
+        assert_eq!(aligned_hashes, 20);
+        assert_eq!(offset_secs, 0.0);
+    }
+
+    #[tokio::test]
+    async fn every_occurrence_votes_unless_the_hash_is_common() {
+        let config = any_match();
+        // hash 7 four times and hash 9 once more than allowed, both a second into the song
+        let repeated = (0..4).map(|k| (7, 1000 + k * 1000));
+        let common = (0..=config.max_occurrences as u64).map(|k| (9, 1000 + k * 1000));
+        let store = store(
+            vec![("song", repeated.chain(common).collect())],
+            HASH_VERSION,
+        )
+        .await;
+        let query = (0..4)
+            .map(|k| (7, k * 1000))
+            .chain((0..=config.max_occurrences as u64).map(|k| (9, k * 1000)))
+            .collect::<Vec<_>>();
+        let query = fingerprint(query, HASH_VERSION);
+
+        let (_, aligned_hashes, offset_secs, _) =
+            matched(find_match(&store, &query, &config).await.unwrap());
+        assert_eq!(aligned_hashes, 4);
+        assert_eq!(offset_secs, 1.0);
+
+        let uncapped = MatcherConfig {
+            max_occurrences: config.max_occurrences + 1,
+            ..config
+        };
+        let (_, aligned_hashes, ..) = matched(find_match(&store, &query, &uncapped).await.unwrap());
+        assert_eq!(aligned_hashes, 4 + uncapped.max_occurrences);
+    }
+
+    /// Ratio hashes of every peak with the two and three peaks after it, at the time of the peak.
+    fn triplets(peaks: &[(u64, usize)]) -> Vec<(u64, u64)> {
+        peaks
+            .windows(4)
+            .flat_map(|w| {
+                let (time, freq) = w[0];
+                [(w[1], w[2]), (w[1], w[3]), (w[2], w[3])].map(|((tb, fb), (tc, fc))| {
+                    let deltas = ((tb - time) as usize, (tc - time) as usize);
+                    (encode_ratio_hash(freq, (fb, fc), deltas), time)
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn finds_speed_of_faster_query() {
+        let mut seed = 7u32;
+        let mut next = |range: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) % range
+        };
+        let mut time = 10_025;
+        let peaks = (0..200)
+            .map(|_| {
+                time += 500 + next(1000) as u64;
+                (time, 300 + next(3000) as usize)
+            })
+            .collect::<Vec<_>>();
+        let store = store(vec![("song", triplets(&peaks))], RATIO_HASH_VERSION).await;
+
+        // played 5% faster from 10.025 s into the song, every peak comes sooner and higher
+        let faster = peaks
+            .iter()
+            .map(|&(time, freq)| {
+                let time = ((time - 10_025) as f64 / 1.05).round() as u64;
+                (time, (freq as f64 * 1.05).round() as usize)
+            })
+            .collect::<Vec<_>>();
+        let query = fingerprint(triplets(&faster), RATIO_HASH_VERSION);
+
+        let result = find_match(&store, &query, &MatcherConfig::default())
+            .await
+            .unwrap();
+        let (id, _, offset_secs, speed) = matched(result);
+        assert_eq!(id, "song");
+        assert!((speed - 1.05).abs() < SPEED_STEP / 2.0, "speed {}", speed);
+        assert_eq!(offset_secs, 10.0);
+    }
+}
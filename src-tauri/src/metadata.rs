@@ -0,0 +1,290 @@
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    io::MediaSourceStream,
+    meta::{MetadataRevision, StandardTagKey, StandardVisualKey, Visual},
+    probe::{Hint, ProbeResult},
+};
+use symphonia::default::get_probe;
+
+use crate::schema::{Album, Artist, Artists, ImageItem, TrackResult};
+
+/// Tags and artwork embedded in an audio file, from ID3v2, Vorbis comments or MP4 atoms.
+#[derive(Clone, Debug, Default)]
+pub struct EmbeddedMetadata {
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    /// Release date as tagged, often only a year.
+    pub date: Option<String>,
+    pub label: Option<String>,
+    pub copyright: Option<String>,
+    pub isrc: Option<String>,
+    /// Duration in seconds from the container, `None` when it does not record one.
+    pub duration: Option<f64>,
+    pub cover: Option<Cover>,
+}
+
+/// Prefix of the image URL of a track whose cover is kept in the store, see
+/// [`crate::store::FingerprintStore::set_cover`].
+pub const COVER_URL_PREFIX: &str = "cover:";
+
+/// Image URL referencing the stored cover of the song `id`.
+pub fn cover_url(id: &str) -> String {
+    format!("{}{}", COVER_URL_PREFIX, id)
+}
+
+/// Embedded artwork with its MIME type.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cover {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Cover {
+    /// The image as a `data:` URL, for the frontend to display.
+    pub fn to_data_url(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.media_type,
+            STANDARD.encode(&self.data)
+        )
+    }
+}
+
+/// Reads the tags of an audio stream without decoding it, `extension` helps the probe pick the format.
+pub fn read_metadata(mss: MediaSourceStream, extension: Option<&str>) -> Result<EmbeddedMetadata> {
+    Ok(EmbeddedMetadata::from_probe(&mut probe(mss, extension)?))
+}
+
+/// Detects the format of an audio stream, `extension` helps pick it.
+pub fn probe(mss: MediaSourceStream, extension: Option<&str>) -> Result<ProbeResult> {
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+    Ok(get_probe().format(&hint, mss, &Default::default(), &Default::default())?)
+}
+
+impl EmbeddedMetadata {
+    /// Tags of a probed stream, its format reader can still decode the audio afterwards.
+    pub fn from_probe(probe: &mut ProbeResult) -> Self {
+        let mut metadata = EmbeddedMetadata::default();
+        // tags in front of the stream, like ID3v2, are read by the probe, container tags by the
+        // format reader, which take precedence
+        if let Some(revision) = probe.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.collect(revision);
+        }
+        if let Some(revision) = probe.format.metadata().current() {
+            metadata.collect(revision);
+        }
+        metadata.duration = probe.format.default_track().and_then(|track| {
+            let params = &track.codec_params;
+            Some(params.n_frames? as f64 / params.sample_rate? as f64)
+        });
+        metadata
+    }
+
+    fn collect(&mut self, revision: &MetadataRevision) {
+        let mut artists = vec![];
+        let mut album_artists = vec![];
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let field = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::Album) => &mut self.album,
+                Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) => &mut self.date,
+                Some(StandardTagKey::OriginalDate) if self.date.is_none() => &mut self.date,
+                Some(StandardTagKey::Label) => &mut self.label,
+                Some(StandardTagKey::Copyright) => &mut self.copyright,
+                Some(StandardTagKey::IdentIsrc) => &mut self.isrc,
+                Some(StandardTagKey::Artist) => {
+                    artists.extend(split_artists(value));
+                    continue;
+                }
+                Some(StandardTagKey::AlbumArtist) => {
+                    album_artists.extend(split_artists(value));
+                    continue;
+                }
+                _ => continue,
+            };
+            *field = Some(value.to_string());
+        }
+        if artists.is_empty() {
+            artists = album_artists;
+        }
+        if !artists.is_empty() {
+            self.artists = artists;
+        }
+        if let Some(cover) = front_cover(revision.visuals()) {
+            self.cover = Some(cover);
+        }
+    }
+
+    /// Year of the release date, when it starts with one.
+    pub fn year(&self) -> Option<String> {
+        let year = self.date.as_deref()?.get(..4)?;
+        year.chars()
+            .all(|c| c.is_ascii_digit())
+            .then(|| year.to_string())
+    }
+
+    /// Fills the fields `track` is missing, metadata from the source it was found on is kept.
+    pub fn fill(&self, track: &mut TrackResult) {
+        fill(&mut track.year, self.year());
+        fill(&mut track.release_date, self.date.clone());
+        fill(&mut track.duration, self.duration);
+        fill(&mut track.label, self.label.clone());
+        fill(&mut track.copyright, self.copyright.clone());
+        fill(&mut track.isrc, self.isrc.clone());
+        fill(&mut track.album.name, self.album.clone());
+        if track.name.is_empty()
+            && let Some(title) = &self.title
+        {
+            track.name = title.clone();
+        }
+        if track.artists.primary.is_empty() && !self.artists.is_empty() {
+            let artists = self
+                .artists
+                .iter()
+                .map(|name| Artist {
+                    id: String::new(),
+                    name: name.clone(),
+                    role: "Artist".to_string(),
+                    kind: "person".to_string(),
+                    image: vec![],
+                    url: String::new(),
+                })
+                .collect::<Vec<Artist>>();
+            track.artists = Artists {
+                primary: artists.clone(),
+                featured: track.artists.featured.clone(),
+                all: artists,
+            };
+        }
+        // only referenced, the image itself is stored apart from the track
+        if track.image.is_empty() && self.cover.is_some() {
+            track.image.push(ImageItem {
+                quality: "embedded".to_string(),
+                url: cover_url(&track.id),
+            });
+        }
+    }
+
+    /// The cover, if [`EmbeddedMetadata::fill`] made `track` reference it so it has
+    /// to be stored along with the song.
+    pub fn into_cover(self, track: &TrackResult) -> Option<Cover> {
+        let url = cover_url(&track.id);
+        self.cover
+            .filter(|_| track.image.iter().any(|image| image.url == url))
+    }
+
+    /// Metadata of a song known only from its file, `name` is used when it has no title tag.
+    pub fn to_track(&self, id: String, name: String, url: String) -> TrackResult {
+        let mut track = TrackResult {
+            id,
+            name: self.title.clone().unwrap_or(name),
+            kind: "local".to_string(),
+            year: None,
+            release_date: None,
+            duration: None,
+            label: None,
+            explicit_content: false,
+            play_count: None,
+            language: "unknown".to_string(),
+            has_lyrics: false,
+            lyrics_id: None,
+            url,
+            copyright: None,
+            isrc: None,
+            album: Album {
+                id: None,
+                name: None,
+                url: None,
+            },
+            artists: Artists {
+                primary: vec![],
+                featured: vec![],
+                all: vec![],
+            },
+            image: vec![],
+            download_url: vec![],
+        };
+        self.fill(&mut track);
+        track
+    }
+}
+
+fn fill<T>(field: &mut Option<T>, value: Option<T>) {
+    if field.is_none() {
+        *field = value;
+    }
+}
+
+/// Splits a multi-valued artist tag, ID3v2.4 separates values with NUL and most taggers with `;`.
+fn split_artists(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(['\0', ';'])
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// The front cover, or the first image when none is marked as such.
+fn front_cover(visuals: &[Visual]) -> Option<Cover> {
+    let visual = visuals
+        .iter()
+        .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+        .or(visuals.first())?;
+    Some(Cover {
+        media_type: visual.media_type.clone(),
+        data: visual.data.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::ImageItem;
+
+    fn with_cover() -> EmbeddedMetadata {
+        EmbeddedMetadata {
+            cover: Some(Cover {
+                media_type: "image/jpeg".to_string(),
+                data: vec![0xff, 0xd8, 0xff],
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cover_is_referenced_not_embedded() {
+        let metadata = with_cover();
+        let track = metadata.to_track("id".to_string(), "name".to_string(), String::new());
+        assert_eq!(track.image.len(), 1);
+        assert_eq!(track.image[0].url, "cover:id");
+        assert_eq!(metadata.into_cover(&track), with_cover().cover);
+    }
+
+    #[test]
+    fn cover_is_dropped_when_the_track_has_an_image() {
+        let mut track = EmbeddedMetadata::default().to_track(
+            "id".to_string(),
+            "name".to_string(),
+            String::new(),
+        );
+        track.image.push(ImageItem {
+            quality: "500x500".to_string(),
+            url: "https://example.com/cover.jpg".to_string(),
+        });
+        let metadata = with_cover();
+        metadata.fill(&mut track);
+        assert_eq!(track.image.len(), 1);
+        assert!(metadata.into_cover(&track).is_none());
+    }
+}
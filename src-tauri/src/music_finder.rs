@@ -1,70 +1,144 @@
+use std::sync::Mutex;
+
+use crate::retry::{RetryConfig, with_retry};
 use crate::schema::{Album, Artist, Artists, DownloadUrlItem, ImageItem, TrackList, TrackResult};
-use anyhow::Result;
-use reqwest::Client;
+use crate::settings::{Settings, api_url};
+use anyhow::{Result, anyhow};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-pub async fn find_jiosaavn_music(client: &Client, url: &str) -> Result<Vec<TrackResult>> {
-    let jiosaavn_api_url = env!("JIOSAAVAN_API_URL");
-    let res = client
-        .get(format!("{}/api/songs", jiosaavn_api_url))
-        .query(&[("link", url)])
-        .send()
-        .await?
-        .json::<TrackList>()
-        .await?;
+pub async fn find_jiosaavn_music(
+    client: &Client,
+    settings: &Settings,
+    url: &str,
+) -> Result<Vec<TrackResult>> {
+    let jiosaavn_api_url = api_url(&settings.jiosaavn_api_url, "JioSaavn API")?;
+    let res = fetch_json::<TrackList>(&settings.retry, || {
+        client
+            .get(format!("{}/api/songs", jiosaavn_api_url))
+            .query(&[("link", url)])
+    })
+    .await?;
     Ok(res.data)
 }
 
-pub async fn get_jiosaavan_url(val: TrackResult) -> String {
+pub async fn get_jiosaavan_url(val: TrackResult) -> Result<String> {
     let downloads = &val.download_url;
-    if downloads.len() < 5 {
-        downloads[downloads.len() - 1].url.clone()
-    } else {
-        downloads[4].url.clone()
-    }
+    // the fifth one is the highest quality, otherwise the last one is the best there is
+    let download = downloads
+        .get(4)
+        .or(downloads.last())
+        .ok_or(anyhow!("No download URL for {}", val.name))?;
+    Ok(download.url.clone())
+}
+
+/// Downloads a JioSaavn song, `progress` is called with the bytes read so far and the total size when known.
+pub async fn download_jiosaavn_music(
+    client: &Client,
+    settings: &Settings,
+    val: TrackResult,
+    progress: impl FnMut(u64, Option<u64>),
+) -> Result<Vec<u8>> {
+    let url = get_jiosaavan_url(val).await?;
+    download(&settings.retry, || client.get(&url), progress).await
 }
 
-pub async fn download_jiosaavn_music(client: &Client, val: TrackResult) -> Result<Vec<u8>> {
-    let url = get_jiosaavan_url(val).await;
-    let res = client.get(url).send().await?.bytes().await?;
-    Ok(res.to_vec())
+pub async fn find_spotify_music(
+    client: &Client,
+    settings: &Settings,
+    url: &str,
+) -> Result<Vec<TrackResult>> {
+    let music_finder_api_url = api_url(&settings.music_finder_api_url, "music finder API")?;
+    fetch_json(&settings.retry, || {
+        client
+            .post(format!("{}/spotify", music_finder_api_url))
+            .json(&json!({"url": url}))
+    })
+    .await
 }
 
-pub async fn find_spotify_music(client: &Client, url: &str) -> Result<Vec<TrackResult>> {
-    let music_finder_api_url = env!("MUSIC_FINDER_API_URL");
-    let res = client
-        .post(format!("{}/spotify", music_finder_api_url))
-        .json(&json!({"url": url}))
-        .send()
-        .await?
-        .json::<Vec<TrackResult>>()
-        .await?;
-    Ok(res)
+pub async fn find_youtube_music(
+    client: &Client,
+    settings: &Settings,
+    url: &str,
+) -> Result<Vec<TrackResult>> {
+    let music_finder_api_url = api_url(&settings.music_finder_api_url, "music finder API")?;
+    fetch_json(&settings.retry, || {
+        client
+            .post(format!("{}/youtube", music_finder_api_url))
+            .json(&json!({"url": url}))
+    })
+    .await
 }
 
-pub async fn find_youtube_music(client: &Client, url: &str) -> Result<Vec<TrackResult>> {
-    let music_finder_api_url = env!("MUSIC_FINDER_API_URL");
-    let res = client
-        .post(format!("{}/youtube", music_finder_api_url))
-        .json(&json!({"url": url}))
-        .send()
-        .await?
-        .json::<Vec<TrackResult>>()
-        .await?;
-    Ok(res)
+/// Downloads the audio of a YouTube video, reporting progress like [`download_jiosaavn_music`].
+pub async fn download_youtube_music(
+    client: &Client,
+    settings: &Settings,
+    url: &str,
+    progress: impl FnMut(u64, Option<u64>),
+) -> Result<Vec<u8>> {
+    let music_downloader_api_url =
+        api_url(&settings.music_downloader_api_url, "music downloader API")?;
+    let request = || {
+        client
+            .post(format!("{}/youtube", music_downloader_api_url))
+            .json(&json!({"url": url}))
+    };
+    download(&settings.retry, request, progress).await
 }
 
-pub async fn download_youtube_music(client: &Client, url: &str) -> Result<Vec<u8>> {
-    let music_downloader_api_url = env!("MUSIC_DOWNLOADER_API_URL");
-    let res = client
-        .post(format!("{}/youtube", music_downloader_api_url))
-        .json(&json!({"url": url}))
-        .send()
-        .await?
-        .bytes()
-        .await?;
-    Ok(res.to_vec())
+/// Sends the request built by `request` and parses its JSON response, with retries.
+async fn fetch_json<T: DeserializeOwned>(
+    retry: &RetryConfig,
+    request: impl Fn() -> RequestBuilder,
+) -> Result<T> {
+    with_retry(retry, || async {
+        Ok(request()
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<T>()
+            .await?)
+    })
+    .await
+}
+
+/// Sends the request built by `request` and reads the body, with retries. A
+/// retried download starts over, so `progress` can go back to zero.
+async fn download(
+    retry: &RetryConfig,
+    request: impl Fn() -> RequestBuilder,
+    progress: impl FnMut(u64, Option<u64>),
+) -> Result<Vec<u8>> {
+    // shared by the attempts, which each borrow it for as long as they run
+    let progress = Mutex::new(progress);
+    with_retry(retry, || async {
+        let res = request().send().await?.error_for_status()?;
+        read_body(res, |read, total| {
+            if let Ok(mut progress) = progress.lock() {
+                progress(read, total);
+            }
+        })
+        .await
+    })
+    .await
+}
+
+/// Reads a response body chunk by chunk, calling `progress` after every chunk.
+async fn read_body(
+    mut res: Response,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<Vec<u8>> {
+    let total = res.content_length();
+    let mut body = Vec::with_capacity(total.unwrap_or(0) as usize);
+    while let Some(chunk) = res.chunk().await? {
+        body.extend_from_slice(&chunk);
+        progress(body.len() as u64, total);
+    }
+    Ok(body)
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -98,6 +172,7 @@ impl YoutubeMusicData {
             has_lyrics: false,
             lyrics_id: None,
             copyright: None,
+            isrc: None,
             album: Album {
                 id: None,
                 name: None,
@@ -134,15 +209,43 @@ impl YoutubeMusicData {
     }
 }
 
-pub async fn get_youtube_music_data(client: &Client, url: &str) -> Result<YoutubeMusicData> {
-    let music_downloader_api_url = env!("MUSIC_DOWNLOADER_API_URL");
-    let res = client
-        .get(format!("{}/youtube", music_downloader_api_url))
-        .json(&json!({"url": url}))
-        .send()
-        .await?
-        .error_for_status()?;
-    println!("Response: {:#?}", res);
-    let res = res.json::<YoutubeMusicData>().await?;
-    Ok(res)
+pub async fn get_youtube_music_data(
+    client: &Client,
+    settings: &Settings,
+    url: &str,
+) -> Result<YoutubeMusicData> {
+    let music_downloader_api_url =
+        api_url(&settings.music_downloader_api_url, "music downloader API")?;
+    fetch_json(&settings.retry, || {
+        client
+            .get(format!("{}/youtube", music_downloader_api_url))
+            .json(&json!({"url": url}))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::EmbeddedMetadata;
+
+    fn track(qualities: &[&str]) -> TrackResult {
+        let mut track = EmbeddedMetadata::default().to_track("a".into(), "a".into(), String::new());
+        track.download_url = qualities
+            .iter()
+            .map(|quality| DownloadUrlItem {
+                quality: quality.to_string(),
+                url: quality.to_string(),
+            })
+            .collect();
+        track
+    }
+
+    #[tokio::test]
+    async fn jiosaavn_url_picks_best_quality() {
+        let all = ["12kbps", "48kbps", "96kbps", "160kbps", "320kbps"];
+        assert_eq!(get_jiosaavan_url(track(&all)).await.unwrap(), "320kbps");
+        assert_eq!(get_jiosaavan_url(track(&all[..3])).await.unwrap(), "96kbps");
+        assert!(get_jiosaavan_url(track(&[])).await.is_err());
+    }
 }
@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+
+use crate::fingerprint::{FingerprintConfig, FrequencyScale, PeakStrategy};
+use crate::utils::{apply_filterbank, frame_peaks, frame_time, mel_filterbank, mel_points};
+
+/// Spectrogram peak as `(time ms, freq Hz, magnitude)`.
+pub(crate) type Peak = (usize, usize, f32);
+
+/// Streaming peak picker fed one spectrogram frame at a time, following the
+/// [`PeakStrategy`] of the config.
+pub(crate) struct PeakPicker {
+    config: FingerprintConfig,
+    sr: usize,
+    axis: FrequencyAxis,
+    /// Index of the next frame pushed.
+    frames: usize,
+    /// Frames around the next one to evaluate, for the constellation strategy.
+    neighbourhood: VecDeque<Frame>,
+    /// Candidates of the current second with their prominence, for the constellation strategy.
+    block: Vec<(f32, Peak)>,
+    /// Second the candidates in `block` belong to.
+    block_index: usize,
+}
+
+/// Frequency axis of the frames handed to the peak strategies, from the
+/// [`FrequencyScale`] of the config.
+struct FrequencyAxis {
+    /// Filterbank applied to the FFT magnitudes, `None` for the FFT bins themselves.
+    filters: Option<Vec<Vec<f32>>>,
+    /// Frequency in Hz of every bin of the axis.
+    freqs: Vec<usize>,
+    /// Band edges in Hz for band maxima.
+    bands: Vec<(usize, usize)>,
+}
+
+impl FrequencyAxis {
+    fn new(sr: u32, config: &FingerprintConfig) -> Self {
+        let fft_size = config.fft_size;
+        match config.frequency_scale {
+            FrequencyScale::Linear => Self {
+                filters: None,
+                freqs: (0..fft_size / 2 + 1)
+                    .map(|j| j * sr as usize / fft_size)
+                    .collect(),
+                bands: config.bands.clone(),
+            },
+            FrequencyScale::Mel {
+                filters,
+                min_freq,
+                max_freq,
+                bands,
+            } => {
+                let points = mel_points(filters, min_freq, max_freq);
+                // every filter is represented by its centre frequency
+                let freqs = points[1..=filters]
+                    .iter()
+                    .map(|&hz| hz as usize)
+                    .collect::<Vec<usize>>();
+                let edges = (0..=bands)
+                    .map(|b| {
+                        freqs
+                            .get(b * filters / bands.max(1))
+                            .copied()
+                            .unwrap_or(usize::MAX)
+                    })
+                    .collect::<Vec<usize>>();
+                Self {
+                    filters: Some(mel_filterbank(sr, fft_size, filters, min_freq, max_freq)),
+                    freqs,
+                    bands: edges.windows(2).map(|w| (w[0], w[1])).collect(),
+                }
+            }
+        }
+    }
+
+    fn apply(&self, magnitudes: Vec<f32>) -> Vec<f32> {
+        match &self.filters {
+            Some(filters) => apply_filterbank(&magnitudes, filters),
+            None => magnitudes,
+        }
+    }
+}
+
+/// Magnitudes of a frame with their maximum and mean over the frequency neighbourhood of every bin.
+struct Frame {
+    magnitudes: Vec<f32>,
+    max: Vec<f32>,
+    mean: Vec<f32>,
+}
+
+impl Frame {
+    fn new(magnitudes: Vec<f32>, freq_radius: usize) -> Self {
+        let n = magnitudes.len();
+        let mut prefix = vec![0.0f32; n + 1];
+        for (j, &x) in magnitudes.iter().enumerate() {
+            prefix[j + 1] = prefix[j] + x;
+        }
+        let (max, mean) = (0..n)
+            .map(|j| {
+                let (lo, hi) = (j.saturating_sub(freq_radius), (j + freq_radius + 1).min(n));
+                let max = magnitudes[lo..hi].iter().fold(0.0f32, |a, &b| a.max(b));
+                (max, (prefix[hi] - prefix[lo]) / (hi - lo) as f32)
+            })
+            .unzip();
+        Self {
+            magnitudes,
+            max,
+            mean,
+        }
+    }
+}
+
+impl PeakPicker {
+    pub fn new(sr: u32, config: &FingerprintConfig) -> Self {
+        Self {
+            config: config.clone(),
+            sr: sr as usize,
+            axis: FrequencyAxis::new(sr, config),
+            frames: 0,
+            neighbourhood: VecDeque::new(),
+            block: vec![],
+            block_index: 0,
+        }
+    }
+
+    /// Feeds the magnitudes of the next frame, returns the peaks that became final, in time order.
+    pub fn push(&mut self, magnitudes: Vec<f32>) -> Vec<Peak> {
+        let i = self.frames;
+        self.frames += 1;
+        let magnitudes = self.axis.apply(magnitudes);
+        match self.config.peak_strategy {
+            PeakStrategy::BandMax => frame_peaks(
+                &magnitudes,
+                i,
+                self.sr,
+                &self.axis.freqs,
+                &self.axis.bands,
+                &self.config,
+            ),
+            PeakStrategy::Constellation {
+                time_radius,
+                freq_radius,
+                ..
+            } => {
+                self.neighbourhood
+                    .push_back(Frame::new(magnitudes, freq_radius));
+                let mut peaks = vec![];
+                // the frame `time_radius` before the newest one has its whole neighbourhood
+                if let Some(centre) = i.checked_sub(time_radius) {
+                    self.evaluate(centre, &mut peaks);
+                }
+                if self.neighbourhood.len() > 2 * time_radius {
+                    self.neighbourhood.pop_front();
+                }
+                peaks
+            }
+        }
+    }
+
+    /// Returns the peaks still held back at the end of the input.
+    pub fn finish(&mut self) -> Vec<Peak> {
+        let mut peaks = vec![];
+        if let PeakStrategy::Constellation { time_radius, .. } = self.config.peak_strategy {
+            for centre in self.frames.saturating_sub(time_radius)..self.frames {
+                self.evaluate(centre, &mut peaks);
+            }
+            self.flush_block(&mut peaks);
+        }
+        peaks
+    }
+
+    /// Time in milliseconds before which every peak has already been returned.
+    pub fn horizon(&self) -> usize {
+        match self.config.peak_strategy {
+            PeakStrategy::BandMax => frame_time(self.frames, self.sr, self.config.hop_size),
+            PeakStrategy::Constellation { .. } => self.block_index * 1000,
+        }
+    }
+
+    /// Collects the local maxima of frame `centre`, emitting the previous second once `centre` leaves it.
+    fn evaluate(&mut self, centre: usize, peaks: &mut Vec<Peak>) {
+        let PeakStrategy::Constellation {
+            time_radius,
+            threshold,
+            ..
+        } = self.config.peak_strategy
+        else {
+            return;
+        };
+        let time = frame_time(centre, self.sr, self.config.hop_size);
+        if time / 1000 > self.block_index {
+            self.flush_block(peaks);
+            self.block_index = time / 1000;
+        }
+
+        let first = self.frames - self.neighbourhood.len();
+        let (lo, hi) = (
+            centre.saturating_sub(time_radius).max(first) - first,
+            (centre + time_radius + 1).min(self.frames) - first,
+        );
+        let frames = self.neighbourhood.range(lo..hi).collect::<Vec<&Frame>>();
+        let frame = &self.neighbourhood[centre - first];
+
+        // the DC bin carries no pitch information
+        let skip = usize::from(self.axis.filters.is_none());
+        for (j, &x) in frame.magnitudes.iter().enumerate().skip(skip) {
+            if x <= self.config.peak_threshold || frames.iter().any(|f| f.max[j] > x) {
+                continue;
+            }
+            let mean = frames.iter().map(|f| f.mean[j]).sum::<f32>() / frames.len() as f32;
+            if x > threshold * mean {
+                self.block.push((x / mean, (time, self.axis.freqs[j], x)));
+            }
+        }
+    }
+
+    /// Keeps the most prominent candidates of the current second up to the target density.
+    fn flush_block(&mut self, peaks: &mut Vec<Peak>) {
+        let PeakStrategy::Constellation {
+            peaks_per_second, ..
+        } = self.config.peak_strategy
+        else {
+            return;
+        };
+        let mut block = std::mem::take(&mut self.block);
+        block.sort_by(|a, b| b.0.total_cmp(&a.0));
+        block.truncate(peaks_per_second);
+        let mut kept = block
+            .into_iter()
+            .map(|(_, peak)| peak)
+            .collect::<Vec<Peak>>();
+        kept.sort_by_key(|&(time, freq, _)| (time, freq));
+        peaks.extend(kept);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SR: usize = 1000;
+    const FFT_SIZE: usize = 16;
+    /// A tenth of a second, so a second holds ten frames.
+    const HOP_SIZE: usize = 100;
+
+    fn config(
+        time_radius: usize,
+        freq_radius: usize,
+        threshold: f32,
+        per_second: usize,
+    ) -> FingerprintConfig {
+        FingerprintConfig {
+            fft_size: FFT_SIZE,
+            hop_size: HOP_SIZE,
+            peak_strategy: PeakStrategy::Constellation {
+                time_radius,
+                freq_radius,
+                threshold,
+                peaks_per_second: per_second,
+            },
+            frequency_scale: FrequencyScale::Linear,
+            ..FingerprintConfig::default()
+        }
+    }
+
+    /// `frames` frames of `background` with the `(frame, bin, magnitude)` spikes on top.
+    fn spectrogram(
+        frames: usize,
+        background: f32,
+        spikes: &[(usize, usize, f32)],
+    ) -> Vec<Vec<f32>> {
+        let mut spectrogram = vec![vec![background; FFT_SIZE / 2 + 1]; frames];
+        for &(i, j, x) in spikes {
+            spectrogram[i][j] = x;
+        }
+        spectrogram
+    }
+
+    fn pick(config: &FingerprintConfig, spectrogram: Vec<Vec<f32>>) -> Vec<Peak> {
+        let mut picker = PeakPicker::new(SR as u32, config);
+        let mut peaks = vec![];
+        for frame in spectrogram {
+            peaks.extend(picker.push(frame));
+        }
+        peaks.extend(picker.finish());
+        peaks
+    }
+
+    fn peak(i: usize, j: usize, x: f32) -> Peak {
+        (frame_time(i, SR, HOP_SIZE), j * SR / FFT_SIZE, x)
+    }
+
+    #[test]
+    fn only_neighbourhood_maxima_are_peaks() {
+        // the spike at (6, 4) is inside the neighbourhood of the louder one at (5, 3)
+        let spikes = [(5, 3, 10.0), (6, 4, 8.0), (15, 6, 9.0)];
+        let peaks = pick(&config(2, 2, 2.0, 30), spectrogram(20, 1.0, &spikes));
+        assert_eq!(peaks, vec![peak(5, 3, 10.0), peak(15, 6, 9.0)]);
+    }
+
+    #[test]
+    fn peaks_must_stand_out_from_the_neighbourhood_mean() {
+        // a local maximum 1.5 times the background, about as much above the mean
+        let spectrogram = || spectrogram(10, 2.0, &[(5, 4, 3.0)]);
+        assert!(pick(&config(2, 2, 2.0, 30), spectrogram()).is_empty());
+        assert_eq!(
+            pick(&config(2, 2, 1.2, 30), spectrogram()),
+            vec![peak(5, 4, 3.0)]
+        );
+    }
+
+    #[test]
+    fn density_limit_keeps_the_most_prominent_peaks() {
+        // five separate peaks within the first second, and one in the next
+        let spikes = [
+            (1, 2, 4.0),
+            (3, 6, 8.0),
+            (5, 2, 5.0),
+            (7, 6, 7.0),
+            (9, 2, 6.0),
+            (13, 4, 2.0),
+        ];
+        let peaks = pick(&config(1, 1, 1.5, 3), spectrogram(20, 1.0, &spikes));
+        assert_eq!(
+            peaks,
+            vec![
+                peak(3, 6, 8.0),
+                peak(7, 6, 7.0),
+                peak(9, 2, 6.0),
+                peak(13, 4, 2.0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::f64::consts::PI;
+
+/// Number of zero crossings of the sinc kept on each side of the filter centre.
+const ZERO_CROSSINGS: usize = 16;
+/// Cutoff as a fraction of the lower of the two Nyquist frequencies.
+const ROLLOFF: f64 = 0.9;
+/// Kaiser window shape, about 80 dB of stopband attenuation.
+const KAISER_BETA: f64 = 8.0;
+/// Upper bound on the number of filter phases, ratios needing more are approximated.
+const MAX_PHASES: u64 = 4096;
+
+/// Band limited resampler by a rational ratio `up / down`, using a Kaiser
+/// windowed-sinc low-pass split into `up` polyphase branches.
+///
+/// Input can be fed in chunks with [`Resampler::process`], the output is
+/// identical to resampling the whole signal at once.
+pub(crate) struct Resampler {
+    up: u64,
+    down: u64,
+    /// Centre of the prototype filter in upsampled samples.
+    delay: u64,
+    /// `phases[p][j]` is tap `p + j * up` of the prototype filter.
+    phases: Vec<Vec<f32>>,
+    buffer: Vec<f32>,
+    /// Absolute index of `buffer[0]` in the input.
+    buffer_start: u64,
+    received: u64,
+    produced: u64,
+}
+
+impl Resampler {
+    pub fn new(sr: u32, target_sr: u32) -> Self {
+        let (up, down) = ratio(sr as u64, target_sr as u64);
+        let cutoff = ROLLOFF * 0.5 / up.max(down) as f64;
+        let half = (ZERO_CROSSINGS as f64 / (2.0 * cutoff)).ceil() as u64;
+        let len = 2 * half + 1;
+
+        let filter = (0..len)
+            .map(|n| {
+                let x = n as f64 - half as f64;
+                2.0 * cutoff * sinc(2.0 * cutoff * x) * kaiser(x / half as f64) * up as f64
+            })
+            .collect::<Vec<f64>>();
+        let taps = len.div_ceil(up) as usize;
+        let phases = (0..up as usize)
+            .map(|p| {
+                (0..taps)
+                    .map(|j| filter.get(p + j * up as usize).copied().unwrap_or(0.0) as f32)
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            up,
+            down,
+            delay: half,
+            phases,
+            buffer: vec![],
+            buffer_start: 0,
+            received: 0,
+            produced: 0,
+        }
+    }
+
+    /// Output sample rate, differs from the requested one only for ratios
+    /// that needed more than [`MAX_PHASES`] phases.
+    pub fn output_rate(&self, sr: u32) -> u32 {
+        (sr as u64 * self.up / self.down) as u32
+    }
+
+    /// Feeds the next chunk of input, returns every output sample whose filter support is complete.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.buffer.extend_from_slice(input);
+        self.received += input.len() as u64;
+
+        let mut output = vec![];
+        while self.next_index() < self.received {
+            output.push(self.next_sample());
+        }
+
+        // history older than the support of the next output is never read again
+        let taps = self.phases[0].len() as u64;
+        let keep_from = (self.next_index() + 1)
+            .saturating_sub(taps)
+            .min(self.received);
+        if keep_from > self.buffer_start {
+            self.buffer
+                .drain(..(keep_from - self.buffer_start) as usize);
+            self.buffer_start = keep_from;
+        }
+        output
+    }
+
+    /// Returns the remaining output, treating the input as zero padded after its end.
+    pub fn flush(&mut self) -> Vec<f32> {
+        let total = (self.received * self.up).div_ceil(self.down);
+        let mut output = vec![];
+        while self.produced < total {
+            output.push(self.next_sample());
+        }
+        output
+    }
+
+    /// Newest input index read by the next output sample.
+    fn next_index(&self) -> u64 {
+        (self.produced * self.down + self.delay) / self.up
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let t = self.produced * self.down + self.delay;
+        let (newest, phase) = ((t / self.up) as i64, (t % self.up) as usize);
+        self.produced += 1;
+
+        self.phases[phase]
+            .iter()
+            .enumerate()
+            .map(|(j, &coeff)| {
+                let i = newest - j as i64;
+                if i < self.buffer_start as i64 || i >= self.received as i64 {
+                    return 0.0;
+                }
+                coeff * self.buffer[(i - self.buffer_start as i64) as usize]
+            })
+            .sum()
+    }
+}
+
+/// Reduced `up / down` ratio from `sr` to `target_sr`, approximated by the best
+/// continued fraction convergent when it needs more than [`MAX_PHASES`] phases.
+fn ratio(sr: u64, target_sr: u64) -> (u64, u64) {
+    let g = gcd(sr, target_sr);
+    let (up, down) = (target_sr / g, sr / g);
+    if up <= MAX_PHASES {
+        return (up, down);
+    }
+
+    let (mut num, mut den) = (target_sr, sr);
+    let (mut p0, mut q0, mut p1, mut q1) = (0, 1, 1, 0);
+    while den != 0 {
+        let a = num / den;
+        let (p2, q2) = (a * p1 + p0, a * q1 + q0);
+        if p2 > MAX_PHASES {
+            break;
+        }
+        (p0, q0, p1, q1) = (p1, q1, p2, q2);
+        (num, den) = (den, num - a * den);
+    }
+    (p1.max(1), q1.max(1))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window at `r` in `-1.0..=1.0`.
+fn kaiser(r: f64) -> f64 {
+    bessel_i0(KAISER_BETA * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term, mut k) = (1.0, 1.0, 1.0);
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, sr: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * freq * n as f64 / sr as f64).sin() as f32)
+            .collect()
+    }
+
+    fn resample(input: &[f32], sr: u32, target_sr: u32, chunk: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(sr, target_sr);
+        let mut output = vec![];
+        for chunk in input.chunks(chunk) {
+            output.extend(resampler.process(chunk));
+        }
+        output.extend(resampler.flush());
+        output
+    }
+
+    /// RMS away from the edges, where the filter runs into the zero padding.
+    fn rms(signal: &[f32]) -> f32 {
+        let middle = &signal[signal.len() / 4..signal.len() * 3 / 4];
+        (middle.iter().map(|x| x * x).sum::<f32>() / middle.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn attenuates_above_new_nyquist() {
+        for (sr, target_sr) in [(44100, 22050), (48000, 22050)] {
+            let input = sine(15000.0, sr, sr as usize);
+            let output = resample(&input, sr, target_sr, input.len());
+            assert!(rms(&output) < 1e-3 * rms(&input), "{sr} -> {target_sr}");
+        }
+    }
+
+    #[test]
+    fn keeps_passband() {
+        for (sr, target_sr) in [(44100, 22050), (48000, 22050), (8000, 22050)] {
+            let input = sine(1000.0, sr, sr as usize);
+            let output = resample(&input, sr, target_sr, input.len());
+            assert_eq!(
+                output.len(),
+                (input.len() as u64 * target_sr as u64).div_ceil(sr as u64) as usize
+            );
+            assert!(
+                (rms(&output) / rms(&input) - 1.0).abs() < 0.01,
+                "{sr} -> {target_sr}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_matches_one_shot() {
+        for (sr, target_sr) in [(44100, 22050), (48000, 22050), (8000, 22050)] {
+            let input = sine(440.0, sr, sr as usize / 2);
+            let one_shot = resample(&input, sr, target_sr, input.len());
+            for chunk in [1, 7, 1000, 4093] {
+                assert_eq!(
+                    resample(&input, sr, target_sr, chunk),
+                    one_shot,
+                    "chunks of {chunk}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use backon::{ExponentialBuilder, Retryable};
+use redis::{RedisError, RetryMethod};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Backoff for network calls to the song APIs and Redis.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryConfig {
+    /// Attempts including the first one, 1 disables retries.
+    pub max_attempts: usize,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Adds a random delay of up to the current one, so clients that failed
+    /// together do not retry together.
+    pub jitter: bool,
+    /// Time limit to connect and to wait for a response or the next chunk of
+    /// one, an attempt that runs into it is retried.
+    pub timeout_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            min_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: true,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    fn backoff(&self) -> ExponentialBuilder {
+        let backoff = ExponentialBuilder::new()
+            .with_min_delay(Duration::from_millis(self.min_delay_ms))
+            .with_max_delay(Duration::from_millis(self.max_delay_ms))
+            .with_max_times(self.max_attempts.saturating_sub(1));
+        if self.jitter {
+            backoff.with_jitter()
+        } else {
+            backoff
+        }
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error [`is_retryable`]
+/// does not accept, or runs out of attempts.
+///
+/// Every attempt starts the operation over, so it has to be safe to repeat.
+pub async fn with_retry<T, F, Fut>(config: &RetryConfig, operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    operation.retry(config.backoff()).when(is_retryable).await
+}
+
+/// Whether an error is likely transient: timeouts, refused or dropped
+/// connections, HTTP 429 and 5xx responses, and Redis asking to try again.
+/// Anything else, like a request that cannot be built, fails the same way
+/// every time.
+pub fn is_retryable(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| {
+                    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+                })
+        } else if let Some(e) = cause.downcast_ref::<RedisError>() {
+            e.is_timeout()
+                || e.is_connection_dropped()
+                || e.is_connection_refusal()
+                || e.is_io_error()
+                || matches!(e.retry_method(), RetryMethod::WaitAndRetry)
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serves `/{status}` with that status and never answers `/slow`, returns its base URL.
+    fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut request = [0; 1024];
+                    let read = stream.read(&mut request).unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    let path = request.split(' ').nth(1).unwrap_or("/");
+                    if path == "/slow" {
+                        std::thread::sleep(Duration::from_secs(5));
+                        return;
+                    }
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        &path[1..]
+                    );
+                });
+            }
+        });
+        url
+    }
+
+    async fn error(client: &reqwest::Client, url: &str) -> anyhow::Error {
+        match client.get(url).send().await {
+            Ok(response) => response.error_for_status().unwrap_err().into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_transient_http_errors_are_retried() {
+        let url = serve();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+
+        for (url, retryable) in [
+            (format!("{}/503", url), true),
+            (format!("{}/429", url), true),
+            (format!("{}/slow", url), true),
+            (closed, true),
+            (format!("{}/404", url), false),
+            (format!("{}/400", url), false),
+            ("not a url".to_string(), false),
+        ] {
+            let e = error(&client, &url).await;
+            assert_eq!(is_retryable(&e), retryable, "{}: {:#}", url, e);
+            // the cause is found under added context too
+            assert_eq!(is_retryable(&e.context("Fetch Error")), retryable);
+        }
+        assert!(!is_retryable(&anyhow::anyhow!("Unknown store backend: x")));
+    }
+}
@@ -40,6 +40,8 @@ pub struct TrackResult {
     pub lyrics_id: Option<String>,
     pub url: String,
     pub copyright: Option<String>,
+    /// International Standard Recording Code, only known from embedded tags.
+    pub isrc: Option<String>,
     pub album: Album,
     pub artists: Artists,
     pub image: Vec<ImageItem>,
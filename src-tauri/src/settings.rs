@@ -0,0 +1,442 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::fingerprint::{FingerprintConfig, FrequencyScale, PIPELINE_VERSION};
+use crate::ingest::DuplicatePolicy;
+use crate::matcher::MatcherConfig;
+use crate::retry::RetryConfig;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum StoreBackend {
+    #[default]
+    Redis,
+    /// On-disk database in the app data dir.
+    Sled,
+    /// Kept in memory and lost on exit.
+    Memory,
+}
+
+/// Service locations, network behaviour, match thresholds and the fingerprint
+/// config, editable while the app runs.
+///
+/// Loaded from the defaults, overridden by environment variables (also read
+/// from a `.env` file), overridden by `settings.json` in the app data dir,
+/// which is what [`Settings::save`] writes. Only settings that differ from the
+/// environment are saved. Service locations default to empty, nothing from the
+/// build environment ends up in the binary.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    /// `STORE_BACKEND`
+    pub store_backend: StoreBackend,
+    /// `REDIS_URI`, `rediss://` connects over TLS.
+    pub redis_uri: String,
+    /// `MUSIC_FINDER_API_URL`, resolves Spotify and YouTube links.
+    pub music_finder_api_url: String,
+    /// `JIOSAAVAN_API_URL`
+    pub jiosaavn_api_url: String,
+    /// `MUSIC_DOWNLOADER_API_URL`, downloads YouTube audio.
+    pub music_downloader_api_url: String,
+    pub retry: RetryConfig,
+    pub matcher: MatcherConfig,
+    /// What ingesting a recording that is already stored under another id does.
+    pub duplicate_policy: DuplicatePolicy,
+    /// Config new databases are fingerprinted with, a database built with another
+    /// one has to be ingested again. Its version is always the current one.
+    pub fingerprint: FingerprintConfig,
+}
+
+/// A setting that cannot be used, `field` is its camelCase name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SettingsIssue {
+    pub field: String,
+    pub message: String,
+}
+
+impl Settings {
+    /// Never fails so a bad setting cannot keep the app from starting, a source
+    /// that cannot be used is skipped and what went wrong is returned with the
+    /// settings.
+    pub fn load(data_dir: &Path) -> (Self, Vec<String>) {
+        let (mut settings, mut problems) = Self::from_env();
+        let path = data_dir.join(SETTINGS_FILE);
+        if path.exists() {
+            match Self::merge_file(&settings, &path) {
+                Ok(merged) => settings = merged,
+                Err(e) => problems.push(format!("Ignored {}: {:#}", path.display(), e)),
+            }
+        }
+        (settings, problems)
+    }
+
+    /// The defaults with the environment variables over them, what the saved
+    /// settings are applied to.
+    fn from_env() -> (Self, Vec<String>) {
+        // a missing .env file is the normal case
+        let _ = dotenvy::dotenv();
+        let mut settings = Settings::default();
+        let mut problems = vec![];
+        let from_env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        if let Some(backend) = from_env("STORE_BACKEND") {
+            match serde_json::from_value(Value::String(backend.clone())) {
+                Ok(backend) => settings.store_backend = backend,
+                Err(_) => problems.push(format!("Unknown store backend: {}", backend)),
+            }
+        }
+        for (name, field) in [
+            ("REDIS_URI", &mut settings.redis_uri),
+            ("MUSIC_FINDER_API_URL", &mut settings.music_finder_api_url),
+            ("JIOSAAVAN_API_URL", &mut settings.jiosaavn_api_url),
+            (
+                "MUSIC_DOWNLOADER_API_URL",
+                &mut settings.music_downloader_api_url,
+            ),
+        ] {
+            if let Some(value) = from_env(name) {
+                *field = value;
+            }
+        }
+        (settings, problems)
+    }
+
+    /// `settings` with the ones saved at `path` over them.
+    fn merge_file(settings: &Settings, path: &Path) -> Result<Self> {
+        // fields the file does not have, like ones added after it was saved, keep their value
+        let mut value = serde_json::to_value(settings)?;
+        merge(&mut value, serde_json::from_slice(&std::fs::read(path)?)?);
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the settings that differ from the environment, the others keep
+    /// following it when it changes.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let environment = serde_json::to_value(Self::from_env().0)?;
+        let Value::Object(mut fields) = serde_json::to_value(self)? else {
+            return Err(anyhow!("Settings are not an object"));
+        };
+        fields.retain(|name, value| environment.get(name) != Some(value));
+        std::fs::create_dir_all(data_dir)?;
+        std::fs::write(
+            data_dir.join(SETTINGS_FILE),
+            serde_json::to_vec_pretty(&fields)?,
+        )?;
+        Ok(())
+    }
+
+    /// Every problem with the settings, empty when they are usable. An empty
+    /// API URL is allowed and only fails the features that need it.
+    pub fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = vec![];
+        let mut issue = |field: &str, message: String| {
+            issues.push(SettingsIssue {
+                field: field.to_string(),
+                message,
+            })
+        };
+
+        if self.store_backend == StoreBackend::Redis {
+            if self.redis_uri.is_empty() {
+                issue(
+                    "redisUri",
+                    "Redis URI is required by the Redis backend".to_string(),
+                );
+            } else if redis::parse_redis_url(&self.redis_uri).is_none() {
+                issue("redisUri", format!("Invalid Redis URI: {}", self.redis_uri));
+            }
+        }
+        for (field, url) in [
+            ("musicFinderApiUrl", &self.music_finder_api_url),
+            ("jiosaavnApiUrl", &self.jiosaavn_api_url),
+            ("musicDownloaderApiUrl", &self.music_downloader_api_url),
+        ] {
+            if url.is_empty() {
+                continue;
+            }
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                Ok(parsed) => issue(
+                    field,
+                    format!("Unsupported URL scheme: {}", parsed.scheme()),
+                ),
+                Err(e) => issue(field, format!("Invalid URL {}: {}", url, e)),
+            }
+        }
+
+        let retry = &self.retry;
+        if retry.max_attempts == 0 {
+            issue(
+                "retry.maxAttempts",
+                "At least one attempt is needed".to_string(),
+            );
+        }
+        if retry.min_delay_ms > retry.max_delay_ms {
+            issue(
+                "retry.minDelayMs",
+                "Minimum delay is longer than the maximum delay".to_string(),
+            );
+        }
+        if retry.timeout_secs == 0 {
+            issue(
+                "retry.timeoutSecs",
+                "Timeout has to be at least a second".to_string(),
+            );
+        }
+
+        let matcher = &self.matcher;
+        for (field, confidence) in [
+            ("matcher.minConfidence", matcher.min_confidence),
+            ("matcher.duplicateConfidence", matcher.duplicate_confidence),
+        ] {
+            if !(0.0..=1.0).contains(&confidence) {
+                issue(field, "Confidence has to be between 0 and 1".to_string());
+            }
+        }
+        if !(0.0..1.0).contains(&matcher.max_speed_change) {
+            issue(
+                "matcher.maxSpeedChange",
+                "Speed change has to be at least 0 and below 1".to_string(),
+            );
+        }
+
+        let fingerprint = &self.fingerprint;
+        for (field, value) in [
+            ("fingerprint.fftSize", fingerprint.fft_size),
+            ("fingerprint.hopSize", fingerprint.hop_size),
+            ("fingerprint.fanOut", fingerprint.fan_out),
+            (
+                "fingerprint.targetSampleRate",
+                fingerprint.target_sample_rate as usize,
+            ),
+        ] {
+            if value == 0 {
+                issue(field, "Has to be greater than 0".to_string());
+            }
+        }
+        if !fingerprint.fft_size.is_power_of_two() {
+            issue(
+                "fingerprint.fftSize",
+                "FFT size has to be a power of two".to_string(),
+            );
+        }
+        if fingerprint.hop_size > fingerprint.fft_size {
+            issue(
+                "fingerprint.hopSize",
+                "Hop size is larger than the FFT size".to_string(),
+            );
+        }
+        let nyquist = fingerprint.target_sample_rate as usize / 2;
+        match fingerprint.frequency_scale {
+            FrequencyScale::Linear => {
+                // the last band may reach past the Nyquist frequency and ends at the last bin
+                let mut previous_end = 0;
+                for &(start, end) in &fingerprint.bands {
+                    let problem = if start >= end {
+                        "is empty"
+                    } else if start < previous_end {
+                        "overlaps the band before it"
+                    } else if start >= nyquist {
+                        "starts above the Nyquist frequency"
+                    } else {
+                        previous_end = end;
+                        continue;
+                    };
+                    issue(
+                        "fingerprint.bands",
+                        format!("Band {}-{} Hz {}", start, end, problem),
+                    );
+                    break;
+                }
+                if fingerprint.bands.is_empty() {
+                    issue(
+                        "fingerprint.bands",
+                        "At least one band is needed".to_string(),
+                    );
+                }
+            }
+            FrequencyScale::Mel {
+                min_freq, max_freq, ..
+            } => {
+                if min_freq >= max_freq {
+                    issue(
+                        "fingerprint.frequencyScale",
+                        "Lowest mel frequency has to be below the highest".to_string(),
+                    );
+                }
+                if max_freq > nyquist as f32 {
+                    issue(
+                        "fingerprint.frequencyScale",
+                        "Highest mel frequency is above the Nyquist frequency".to_string(),
+                    );
+                }
+            }
+        }
+        issues
+    }
+
+    /// Whether going from `previous` to these settings needs the store to be opened again.
+    pub fn store_changed(&self, previous: &Settings) -> bool {
+        self.store_backend != previous.store_backend
+            || self.fingerprint != previous.fingerprint
+            || (self.store_backend == StoreBackend::Redis
+                && (self.redis_uri != previous.redis_uri || self.retry != previous.retry))
+    }
+
+    /// The fingerprint config to ingest and query with.
+    pub fn fingerprint_config(&self) -> FingerprintConfig {
+        FingerprintConfig {
+            version: PIPELINE_VERSION,
+            ..self.fingerprint.clone()
+        }
+    }
+
+    /// Client for the song APIs, with the timeouts of [`Settings::retry`].
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        Ok(reqwest::Client::builder()
+            .use_rustls_tls()
+            .connect_timeout(self.retry.timeout())
+            .read_timeout(self.retry.timeout())
+            .build()?)
+    }
+}
+
+/// Base URL of an API, failing when it has not been configured.
+pub fn api_url<'a>(url: &'a str, name: &str) -> Result<&'a str> {
+    if url.is_empty() {
+        return Err(anyhow!("The {} URL is not set", name));
+    }
+    Ok(url.trim_end_matches('/'))
+}
+
+/// Overwrites the fields of `base` with those of `overlay`, recursing into objects.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_file_is_reported_instead_of_failing() {
+        let dir = std::env::temp_dir().join(format!("settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(SETTINGS_FILE), "{ \"retry\": ").unwrap();
+        let (settings, problems) = Settings::load(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(settings.retry, RetryConfig::default());
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(SETTINGS_FILE));
+    }
+
+    #[test]
+    fn duplicate_policy_is_saved() {
+        let dir = std::env::temp_dir().join(format!("settings-policy-{}", std::process::id()));
+        let settings = Settings {
+            duplicate_policy: DuplicatePolicy::Reject,
+            ..Settings::default()
+        };
+        settings.save(&dir).unwrap();
+        let (loaded, problems) = Settings::load(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(problems.is_empty());
+        assert_eq!(loaded.duplicate_policy, DuplicatePolicy::Reject);
+    }
+
+    #[test]
+    fn only_settings_changed_from_the_environment_are_saved() {
+        let dir = std::env::temp_dir().join(format!("settings-changed-{}", std::process::id()));
+        let (environment, _) = Settings::from_env();
+        let settings = Settings {
+            duplicate_policy: DuplicatePolicy::Reject,
+            ..environment.clone()
+        };
+        settings.save(&dir).unwrap();
+        let saved: Value =
+            serde_json::from_slice(&std::fs::read(dir.join(SETTINGS_FILE)).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(saved, serde_json::json!({ "duplicatePolicy": "reject" }));
+    }
+
+    #[test]
+    fn unusable_fingerprint_config_is_reported() {
+        let fields = |fingerprint: FingerprintConfig| {
+            Settings {
+                store_backend: StoreBackend::Memory,
+                fingerprint,
+                ..Settings::default()
+            }
+            .validate()
+            .into_iter()
+            .map(|issue| issue.field)
+            .collect::<Vec<_>>()
+        };
+        let default = FingerprintConfig::default();
+        assert!(fields(default.clone()).is_empty());
+        assert!(
+            fields(FingerprintConfig {
+                frequency_scale: FrequencyScale::mel(),
+                ..default.clone()
+            })
+            .is_empty()
+        );
+
+        assert_eq!(
+            fields(FingerprintConfig {
+                fft_size: 1000,
+                hop_size: 1024,
+                ..default.clone()
+            }),
+            ["fingerprint.fftSize", "fingerprint.hopSize"]
+        );
+        for bands in [
+            vec![],
+            vec![(0, 512), (256, 1024)],
+            vec![(512, 256)],
+            vec![(0, 512), (12000, 16000)],
+        ] {
+            assert_eq!(
+                fields(FingerprintConfig {
+                    bands: bands.clone(),
+                    ..default.clone()
+                }),
+                ["fingerprint.bands"],
+                "{:?}",
+                bands
+            );
+        }
+        assert_eq!(
+            fields(FingerprintConfig {
+                frequency_scale: FrequencyScale::Mel {
+                    filters: 64,
+                    min_freq: 20.0,
+                    max_freq: 16000.0,
+                    bands: 10,
+                },
+                ..default
+            }),
+            ["fingerprint.frequencyScale"]
+        );
+    }
+}
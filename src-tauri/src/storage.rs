@@ -0,0 +1,264 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, async_runtime::JoinHandle};
+
+use crate::fingerprint::FingerprintConfig;
+use crate::settings::Settings;
+use crate::store::{FingerprintStore, check_compatible, open_store};
+
+/// Event emitted with the [`StorageStatus`] whenever it changes.
+pub const STORAGE_EVENT: &str = "storage-status";
+
+/// How often a connected store is pinged to notice it going away and coming back.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    /// The last attempt failed with `error`, another one follows.
+    Unavailable {
+        error: String,
+    },
+}
+
+/// Connection state together with the problems that leave the store usable.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStatus {
+    #[serde(flatten)]
+    pub connection: ConnectionStatus,
+    pub warnings: Vec<String>,
+}
+
+/// What a warning of the [`StorageStatus`] is about, a new one replaces the last
+/// one of the same kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum WarningKind {
+    /// The database was fingerprinted with another config, it can be browsed but
+    /// matching and ingesting refuse it until the songs are ingested again.
+    FingerprintConfig,
+    /// Part of the settings could not be loaded and was left at its defaults,
+    /// until the settings are saved again.
+    Settings,
+}
+
+/// An open store with the config the app fingerprints with.
+#[derive(Clone)]
+pub struct Storage {
+    pub store: Arc<dyn FingerprintStore>,
+    /// Config from the settings, checked against the one the database was built with.
+    pub fingerprint_config: FingerprintConfig,
+}
+
+/// Returned instead of a [`Storage`] while the store is not connected.
+#[derive(Debug, Clone)]
+pub struct StorageUnavailable(pub ConnectionStatus);
+
+impl fmt::Display for StorageUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            ConnectionStatus::Unavailable { error } => write!(f, "Storage unavailable: {}", error),
+            _ => write!(f, "Storage unavailable: still connecting"),
+        }
+    }
+}
+
+impl std::error::Error for StorageUnavailable {}
+
+struct Connection {
+    status: ConnectionStatus,
+    /// Kept while a connected store is failing its health checks, to be used
+    /// again once it answers.
+    storage: Option<Storage>,
+    warnings: BTreeMap<WarningKind, String>,
+}
+
+impl Connection {
+    fn status(&self) -> StorageStatus {
+        StorageStatus {
+            connection: self.status.clone(),
+            warnings: self.warnings.values().cloned().collect(),
+        }
+    }
+}
+
+/// Connects the store in the background, so the app starts without it and
+/// commands that need it fail with [`StorageUnavailable`] until it is there.
+pub struct StorageManager {
+    app: AppHandle,
+    connection: Arc<RwLock<Connection>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl StorageManager {
+    pub fn new(app: AppHandle) -> Self {
+        Self {
+            app,
+            connection: Arc::new(RwLock::new(Connection {
+                status: ConnectionStatus::Connecting,
+                storage: None,
+                warnings: BTreeMap::new(),
+            })),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// The store, if it is connected and answering.
+    pub fn get(&self) -> Result<Storage, StorageUnavailable> {
+        let connection = self
+            .connection
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        match (&connection.status, &connection.storage) {
+            (ConnectionStatus::Connected, Some(storage)) => Ok(storage.clone()),
+            (status, _) => Err(StorageUnavailable(status.clone())),
+        }
+    }
+
+    pub fn status(&self) -> StorageStatus {
+        self.connection
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .status()
+    }
+
+    /// Shows `warning` about the settings with the status, or clears it.
+    pub fn set_settings_warning(&self, warning: Option<String>) {
+        self.link().change(|connection| match warning {
+            Some(warning) => {
+                connection.warnings.insert(WarningKind::Settings, warning);
+            }
+            None => {
+                connection.warnings.remove(&WarningKind::Settings);
+            }
+        });
+    }
+
+    fn link(&self) -> Link {
+        Link {
+            app: self.app.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+
+    /// Opens the store of `settings` in the background, replacing the current
+    /// one, and retries until it connects.
+    pub fn connect(&self, settings: Settings, data_dir: PathBuf) {
+        let mut task = self.task.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(task) = task.take() {
+            task.abort();
+        }
+        let link = self.link();
+        // the old store is dropped first, sled only opens a database once
+        link.update(ConnectionStatus::Connecting, |connection| {
+            connection.storage = None;
+            connection.warnings.remove(&WarningKind::FingerprintConfig);
+        });
+        *task = Some(tauri::async_runtime::spawn(async move {
+            link.maintain(settings, data_dir).await
+        }));
+    }
+}
+
+/// Handle the background task updates the connection through.
+struct Link {
+    app: AppHandle,
+    connection: Arc<RwLock<Connection>>,
+}
+
+impl Link {
+    /// Connects, then keeps checking the store is still there.
+    async fn maintain(&self, settings: Settings, data_dir: PathBuf) {
+        // opening already retries with backoff, this waits between rounds of that
+        let round_delay = Duration::from_millis(settings.retry.max_delay_ms);
+        let (storage, config_warning) = loop {
+            match open(&settings, &data_dir).await {
+                Ok(opened) => break opened,
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    self.set_status(ConnectionStatus::Unavailable { error });
+                    tokio::time::sleep(round_delay).await;
+                }
+            }
+        };
+        let store = storage.store.clone();
+        self.update(ConnectionStatus::Connected, |connection| {
+            connection.storage = Some(storage);
+            if let Some(warning) = config_warning {
+                connection
+                    .warnings
+                    .insert(WarningKind::FingerprintConfig, warning);
+            }
+        });
+
+        loop {
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+            let status = match store.ping().await {
+                Ok(()) => ConnectionStatus::Connected,
+                Err(e) => ConnectionStatus::Unavailable {
+                    error: format!("{:#}", e),
+                },
+            };
+            self.set_status(status);
+        }
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        self.update(status, |_| {});
+    }
+
+    /// Applies `change` and the new status, emitting an event if either changed the [`StorageStatus`].
+    fn update(&self, status: ConnectionStatus, change: impl FnOnce(&mut Connection)) {
+        self.change(|connection| {
+            change(connection);
+            connection.status = status;
+        });
+    }
+
+    /// Applies `change`, emitting an event if it changed the [`StorageStatus`].
+    fn change(&self, change: impl FnOnce(&mut Connection)) {
+        let changed = {
+            let mut connection = self
+                .connection
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            let before = connection.status();
+            change(&mut connection);
+            let after = connection.status();
+            (after != before).then_some(after)
+        };
+        if let Some(status) = changed {
+            let _ = self.app.emit(STORAGE_EVENT, &status);
+        }
+    }
+}
+
+/// Opens the store of `settings`, with a warning when the database was built with
+/// another fingerprint config than the settings ask for.
+async fn open(settings: &Settings, data_dir: &Path) -> Result<(Storage, Option<String>)> {
+    let store: Arc<dyn FingerprintStore> = open_store(data_dir, settings).await?.into();
+    let fingerprint_config = settings.fingerprint_config();
+    let warning = match store.get_config().await? {
+        Some(stored) => check_compatible(&stored, &fingerprint_config)
+            .err()
+            .map(|e| e.to_string()),
+        None => None,
+    };
+    Ok((
+        Storage {
+            store,
+            fingerprint_config,
+        },
+        warning,
+    ))
+}
@@ -0,0 +1,891 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::Path,
+    sync::RwLock,
+};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use bincode::{
+    config::standard,
+    serde::{decode_from_slice, encode_to_vec},
+};
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde_json::{from_slice, to_vec};
+
+use crate::db::*;
+use crate::fingerprint::{FingerprintConfig, PIPELINE_VERSION};
+use crate::metadata::Cover;
+use crate::retry::{RetryConfig, with_retry};
+use crate::schema::TrackResult;
+use crate::settings::{Settings, StoreBackend};
+use crate::utils::Data;
+
+/// Storage backend for fingerprint hashes and the track metadata they point to.
+///
+/// Every hash maps to a set of `(time, song_id)` entries, where `time` is the
+/// position of the hash inside the song in milliseconds.
+#[async_trait]
+pub trait FingerprintStore: Send + Sync {
+    async fn ping(&self) -> Result<()>;
+
+    /// Stores all `(hash, time)` pairs of a song together with its metadata, its
+    /// cover if it has one and the [`crate::hash`] layout version the hashes were
+    /// encoded with, in a single write so an ingest cancelled part way leaves
+    /// nothing of the song behind.
+    async fn insert_song(
+        &self,
+        hashes: Vec<(u64, u64)>,
+        track: TrackResult,
+        hash_version: u8,
+        cover: Option<&Cover>,
+    ) -> Result<()>;
+
+    /// Returns the hash layout version of every song, `None` for songs stored
+    /// before versions were recorded, in the order of `ids`.
+    async fn get_hash_versions(&self, ids: &[String]) -> Result<Vec<Option<u8>>>;
+
+    /// Returns the `(time, song_id)` entries of every hash, in the order of `hashes`.
+    async fn lookup_hashes(&self, hashes: &[u64]) -> Result<Vec<Vec<(u64, String)>>>;
+
+    async fn get_track(&self, id: &str) -> Result<Option<TrackResult>>;
+
+    async fn set_track(&self, track: TrackResult) -> Result<()>;
+
+    /// Returns the metadata of every id, in the order of `ids`.
+    async fn get_tracks(&self, ids: &[String]) -> Result<Vec<TrackResult>>;
+
+    async fn get_all_tracks(&self) -> Result<Vec<TrackResult>>;
+
+    /// Stores the artwork of the song `id` apart from its metadata, which only
+    /// references it by [`crate::metadata::cover_url`].
+    async fn set_cover(&self, id: &str, cover: &Cover) -> Result<()>;
+
+    async fn get_cover(&self, id: &str) -> Result<Option<Cover>>;
+
+    /// Removes a song's metadata, cover and every hash entry it contributed.
+    async fn delete_song(&self, id: &str) -> Result<()>;
+
+    /// Records `alias` as another id of the song stored under `id`, for the same
+    /// recording found again from a different source.
+    async fn add_alias(&self, alias: &str, id: &str) -> Result<()>;
+
+    /// Returns the id of the song `alias` was linked to, if any.
+    async fn resolve_alias(&self, alias: &str) -> Result<Option<String>>;
+
+    /// Returns the fingerprint config the stored hashes were computed with.
+    async fn get_config(&self) -> Result<Option<FingerprintConfig>>;
+
+    async fn set_config(&self, config: &FingerprintConfig) -> Result<()>;
+
+    /// Rebuilds the per song reverse index used by [`FingerprintStore::delete_song`]
+    /// for songs stored before it existed, returns the number of songs indexed.
+    async fn rebuild_index(&self) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Records `config` as the database config if none is stored yet,
+/// fails if the stored one is incompatible with it.
+pub async fn ensure_config(store: &dyn FingerprintStore, config: &FingerprintConfig) -> Result<()> {
+    match store.get_config().await? {
+        Some(stored) => check_compatible(&stored, config),
+        None => store.set_config(config).await,
+    }
+}
+
+/// Fails if the database was built with a config incompatible with `config`.
+pub async fn check_config(store: &dyn FingerprintStore, config: &FingerprintConfig) -> Result<()> {
+    match store.get_config().await? {
+        Some(stored) => check_compatible(&stored, config),
+        None => Ok(()),
+    }
+}
+
+/// Fails if hashes computed with `config` cannot be matched against a database built with `stored`.
+pub fn check_compatible(stored: &FingerprintConfig, config: &FingerprintConfig) -> Result<()> {
+    if stored.version != PIPELINE_VERSION {
+        return Err(anyhow!(
+            "The database was fingerprinted with pipeline version {} but this build uses version {}, re-ingest required",
+            stored.version,
+            PIPELINE_VERSION
+        ));
+    }
+    if !stored.is_compatible(config) {
+        return Err(anyhow!(
+            "The database was fingerprinted with {:?} but the settings use {:?}, re-ingest required",
+            stored,
+            config
+        ));
+    }
+    Ok(())
+}
+
+/// Opens the backend selected in `settings`, sled keeps its files in `data_dir`.
+pub async fn open_store(data_dir: &Path, settings: &Settings) -> Result<Box<dyn FingerprintStore>> {
+    match settings.store_backend {
+        StoreBackend::Redis => {
+            let retry = &settings.retry;
+            let client = with_retry(retry, || connect_redis(&settings.redis_uri, retry)).await?;
+            Ok(Box::new(RedisStore::new(client, retry.clone())))
+        }
+        StoreBackend::Sled => Ok(Box::new(SledStore::open(data_dir.join("fingerprints"))?)),
+        StoreBackend::Memory => Ok(Box::new(MemoryStore::default())),
+    }
+}
+
+// ------------------------------------------------------------------------------------------------------
+
+pub struct RedisStore {
+    client: ConnectionManager,
+    retry: RetryConfig,
+}
+
+impl RedisStore {
+    pub fn new(client: ConnectionManager, retry: RetryConfig) -> Self {
+        Self { client, retry }
+    }
+
+    /// Runs `call` with retries, every attempt gets its own handle of the shared connection.
+    async fn retry<T, F, Fut>(&self, mut call: F) -> Result<T>
+    where
+        F: FnMut(ConnectionManager) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        with_retry(&self.retry, || call(self.client.clone())).await
+    }
+}
+
+#[async_trait]
+impl FingerprintStore for RedisStore {
+    async fn ping(&self) -> Result<()> {
+        self.retry(|mut client| async move { Ok(client.ping::<()>().await?) })
+            .await
+    }
+
+    async fn insert_song(
+        &self,
+        hashes: Vec<(u64, u64)>,
+        track: TrackResult,
+        hash_version: u8,
+        cover: Option<&Cover>,
+    ) -> Result<()> {
+        let id = track.id.clone();
+        let songs: Vec<(u64, u64, &str)> = hashes
+            .into_iter()
+            .map(|(hash, time)| (hash, time, id.as_str()))
+            .collect();
+        // the pipeline is atomic and only adds members, so a write that timed
+        // out after being applied can be repeated
+        self.retry(|mut client| {
+            let (songs, track) = (songs.clone(), track.clone());
+            async move { set_all_songs(&mut client, songs, track, hash_version, cover).await }
+        })
+        .await
+    }
+
+    async fn get_hash_versions(&self, ids: &[String]) -> Result<Vec<Option<u8>>> {
+        self.retry(|mut client| async move { get_redis_hash_versions(&mut client, ids).await })
+            .await
+    }
+
+    async fn lookup_hashes(&self, hashes: &[u64]) -> Result<Vec<Vec<(u64, String)>>> {
+        self.retry(|mut client| async move { get_redis_songs(&mut client, hashes).await })
+            .await
+    }
+
+    async fn get_track(&self, id: &str) -> Result<Option<TrackResult>> {
+        self.retry(|mut client| async move {
+            if !client
+                .exists::<String, bool>(format!("song:{}", id))
+                .await?
+            {
+                return Ok(None);
+            }
+            Ok(Some(get_redis_json(&mut client, id).await?))
+        })
+        .await
+    }
+
+    async fn set_track(&self, track: TrackResult) -> Result<()> {
+        self.retry(|mut client| {
+            let track = track.clone();
+            async move { set_redis_json(&mut client, track).await }
+        })
+        .await
+    }
+
+    async fn get_tracks(&self, ids: &[String]) -> Result<Vec<TrackResult>> {
+        self.retry(|mut client| async move { get_redis_jsons(&mut client, ids).await })
+            .await
+    }
+
+    async fn get_all_tracks(&self) -> Result<Vec<TrackResult>> {
+        self.retry(|mut client| async move { get_all_songs(&mut client).await })
+            .await
+    }
+
+    async fn set_cover(&self, id: &str, cover: &Cover) -> Result<()> {
+        self.retry(|mut client| async move { set_redis_cover(&mut client, id, cover).await })
+            .await
+    }
+
+    async fn get_cover(&self, id: &str) -> Result<Option<Cover>> {
+        self.retry(|mut client| async move { get_redis_cover(&mut client, id).await })
+            .await
+    }
+
+    async fn delete_song(&self, id: &str) -> Result<()> {
+        self.retry(|mut client| async move {
+            delete_song(&mut client, id).await?;
+            delete_redis_aliases(&mut client, id).await
+        })
+        .await
+    }
+
+    async fn add_alias(&self, alias: &str, id: &str) -> Result<()> {
+        self.retry(|mut client| async move { set_redis_alias(&mut client, alias, id).await })
+            .await
+    }
+
+    async fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        self.retry(|mut client| async move { get_redis_alias(&mut client, alias).await })
+            .await
+    }
+
+    async fn get_config(&self) -> Result<Option<FingerprintConfig>> {
+        self.retry(|mut client| async move { get_redis_config(&mut client).await })
+            .await
+    }
+
+    async fn set_config(&self, config: &FingerprintConfig) -> Result<()> {
+        self.retry(|mut client| async move { set_redis_config(&mut client, config).await })
+            .await
+    }
+
+    async fn rebuild_index(&self) -> Result<usize> {
+        self.retry(|mut client| async move { build_reverse_index(&mut client).await })
+            .await
+    }
+}
+
+// ------------------------------------------------------------------------------------------------------
+
+/// Local on-disk store, hash entries are kept as concatenated bincode encoded [`Data`] records.
+pub struct SledStore {
+    hashes: sled::Tree,
+    tracks: sled::Tree,
+    /// Bincode encoded [`Cover`] of a song.
+    covers: sled::Tree,
+    song_hashes: sled::Tree,
+    song_versions: sled::Tree,
+    /// Alias to song id.
+    aliases: sled::Tree,
+    /// Reverse index of `aliases`, `{id}\0{alias}` keys.
+    song_aliases: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl SledStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::open(path)?;
+        let hashes = db.open_tree("hashes")?;
+        hashes.set_merge_operator(merge_entry);
+        let tracks = db.open_tree("tracks")?;
+        let covers = db.open_tree("covers")?;
+        let song_hashes = db.open_tree("song_hashes")?;
+        let song_versions = db.open_tree("song_versions")?;
+        let aliases = db.open_tree("aliases")?;
+        let song_aliases = db.open_tree("song_aliases")?;
+        let meta = db.open_tree("meta")?;
+        // a new database never held songs stored before the reverse index
+        if hashes.is_empty() {
+            meta.insert(REVERSE_INDEX_KEY, &[])?;
+        }
+        Ok(Self {
+            hashes,
+            tracks,
+            covers,
+            song_hashes,
+            song_versions,
+            aliases,
+            song_aliases,
+            meta,
+        })
+    }
+
+    fn remove_entries(&self, key: &[u8], id: &str, time: Option<u64>) -> Result<()> {
+        self.hashes.update_and_fetch(key, |old| {
+            let old = old?;
+            let Ok(mut entries) = decode_entries(old) else {
+                return Some(old.to_vec());
+            };
+            entries.retain(|entry| entry.song_id != id || time.is_some_and(|t| t != entry.time));
+            if entries.is_empty() {
+                return None;
+            }
+            encode_entries(&entries).ok()
+        })?;
+        Ok(())
+    }
+}
+
+/// Key in the `meta` tree set once every song is in the reverse index.
+const REVERSE_INDEX_KEY: &str = "reverse_index";
+
+/// Reverse index key, `{id}\0{hash}{time}` so a song's entries share a prefix.
+fn song_hash_key(id: &str, hash: u64, time: u64) -> Vec<u8> {
+    let mut key = song_hash_prefix(id);
+    key.extend_from_slice(&hash.to_be_bytes());
+    key.extend_from_slice(&time.to_be_bytes());
+    key
+}
+
+fn song_hash_prefix(id: &str) -> Vec<u8> {
+    let mut key = id.as_bytes().to_vec();
+    key.push(0);
+    key
+}
+
+fn decode_entries(mut buf: &[u8]) -> Result<Vec<Data>> {
+    let mut entries = vec![];
+    while !buf.is_empty() {
+        let (entry, read): (Data, usize) = decode_from_slice(buf, standard())?;
+        entries.push(entry);
+        buf = &buf[read..];
+    }
+    Ok(entries)
+}
+
+fn encode_entries(entries: &[Data]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    for entry in entries {
+        buf.extend(encode_to_vec(entry, standard())?);
+    }
+    Ok(buf)
+}
+
+/// Appends an entry to a hash unless it is already present, like `SADD`.
+fn merge_entry(_key: &[u8], old: Option<&[u8]>, new: &[u8]) -> Option<Vec<u8>> {
+    let mut buf = old.map(|old| old.to_vec()).unwrap_or_default();
+    let exists = decode_entries(&buf)
+        .ok()
+        .zip(decode_entries(new).ok())
+        .is_some_and(|(old, new)| new.iter().all(|n| old.contains(n)));
+    if !exists {
+        buf.extend_from_slice(new);
+    }
+    Some(buf)
+}
+
+#[async_trait]
+impl FingerprintStore for SledStore {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn insert_song(
+        &self,
+        hashes: Vec<(u64, u64)>,
+        track: TrackResult,
+        hash_version: u8,
+        cover: Option<&Cover>,
+    ) -> Result<()> {
+        for (hash, time) in hashes {
+            let entry = Data {
+                time,
+                song_id: track.id.clone(),
+            };
+            self.hashes
+                .merge(hash.to_be_bytes(), encode_to_vec(entry, standard())?)?;
+            self.song_hashes
+                .insert(song_hash_key(&track.id, hash, time), &[])?;
+        }
+        self.song_versions
+            .insert(track.id.as_bytes(), &[hash_version])?;
+        if let Some(cover) = cover {
+            self.set_cover(&track.id, cover).await?;
+        }
+        self.set_track(track).await
+    }
+
+    async fn get_hash_versions(&self, ids: &[String]) -> Result<Vec<Option<u8>>> {
+        ids.iter()
+            .map(|id| Ok(self.song_versions.get(id)?.and_then(|v| v.first().copied())))
+            .collect()
+    }
+
+    async fn lookup_hashes(&self, hashes: &[u64]) -> Result<Vec<Vec<(u64, String)>>> {
+        hashes
+            .iter()
+            .map(|hash| {
+                let Some(value) = self.hashes.get(hash.to_be_bytes())? else {
+                    return Ok(vec![]);
+                };
+                Ok(decode_entries(&value)?
+                    .into_iter()
+                    .map(|entry| (entry.time, entry.song_id))
+                    .collect())
+            })
+            .collect()
+    }
+
+    async fn get_track(&self, id: &str) -> Result<Option<TrackResult>> {
+        match self.tracks.get(id)? {
+            Some(value) => Ok(Some(from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_track(&self, track: TrackResult) -> Result<()> {
+        self.tracks.insert(track.id.as_bytes(), to_vec(&track)?)?;
+        Ok(())
+    }
+
+    async fn get_tracks(&self, ids: &[String]) -> Result<Vec<TrackResult>> {
+        let mut tracks = Vec::with_capacity(ids.len());
+        for id in ids {
+            tracks.push(
+                self.get_track(id)
+                    .await?
+                    .ok_or(anyhow!("No value found for key: {}", id))?,
+            );
+        }
+        Ok(tracks)
+    }
+
+    async fn get_all_tracks(&self) -> Result<Vec<TrackResult>> {
+        self.tracks
+            .iter()
+            .values()
+            .map(|value| Ok(from_slice(&value?)?))
+            .collect()
+    }
+
+    async fn set_cover(&self, id: &str, cover: &Cover) -> Result<()> {
+        self.covers
+            .insert(id.as_bytes(), encode_to_vec(cover, standard())?)?;
+        Ok(())
+    }
+
+    async fn get_cover(&self, id: &str) -> Result<Option<Cover>> {
+        match self.covers.get(id)? {
+            Some(value) => Ok(Some(decode_from_slice(&value, standard())?.0)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_song(&self, id: &str) -> Result<()> {
+        let prefix = song_hash_prefix(id);
+        let mut indexed = false;
+        for key in self.song_hashes.scan_prefix(&prefix).keys() {
+            let key = key?;
+            let (hash, time) = key[prefix.len()..].split_at(8);
+            let time = u64::from_be_bytes(time.try_into()?);
+            self.remove_entries(hash, id, Some(time))?;
+            self.song_hashes.remove(&key)?;
+            indexed = true;
+        }
+        // songs stored before the reverse index existed, until it has been rebuilt
+        if !indexed
+            && self.tracks.contains_key(id)?
+            && !self.meta.contains_key(REVERSE_INDEX_KEY)?
+        {
+            for key in self.hashes.iter().keys() {
+                self.remove_entries(&key?, id, None)?;
+            }
+        }
+        for key in self.song_aliases.scan_prefix(&prefix).keys() {
+            let key = key?;
+            self.aliases.remove(&key[prefix.len()..])?;
+            self.song_aliases.remove(&key)?;
+        }
+        self.song_versions.remove(id)?;
+        self.covers.remove(id)?;
+        self.tracks.remove(id)?;
+        Ok(())
+    }
+
+    async fn add_alias(&self, alias: &str, id: &str) -> Result<()> {
+        self.aliases.insert(alias.as_bytes(), id.as_bytes())?;
+        let mut key = song_hash_prefix(id);
+        key.extend_from_slice(alias.as_bytes());
+        self.song_aliases.insert(key, &[])?;
+        Ok(())
+    }
+
+    async fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        match self.aliases.get(alias)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_config(&self) -> Result<Option<FingerprintConfig>> {
+        match self.meta.get("config")? {
+            Some(value) => Ok(Some(from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_config(&self, config: &FingerprintConfig) -> Result<()> {
+        self.meta.insert("config", to_vec(config)?)?;
+        Ok(())
+    }
+
+    async fn rebuild_index(&self) -> Result<usize> {
+        let mut songs = HashSet::new();
+        for item in self.hashes.iter() {
+            let (key, value) = item?;
+            let hash = u64::from_be_bytes(key.as_ref().try_into()?);
+            for entry in decode_entries(&value)? {
+                self.song_hashes
+                    .insert(song_hash_key(&entry.song_id, hash, entry.time), &[])?;
+                songs.insert(entry.song_id);
+            }
+        }
+        self.meta.insert(REVERSE_INDEX_KEY, &[])?;
+        Ok(songs.len())
+    }
+}
+
+// ------------------------------------------------------------------------------------------------------
+
+/// Non persistent store, used for tests and for running without any database.
+#[derive(Default)]
+pub struct MemoryStore {
+    hashes: RwLock<HashMap<u64, HashSet<(u64, String)>>>,
+    tracks: RwLock<HashMap<String, TrackResult>>,
+    covers: RwLock<HashMap<String, Cover>>,
+    versions: RwLock<HashMap<String, u8>>,
+    aliases: RwLock<HashMap<String, String>>,
+    config: RwLock<Option<FingerprintConfig>>,
+}
+
+#[async_trait]
+impl FingerprintStore for MemoryStore {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn insert_song(
+        &self,
+        hashes: Vec<(u64, u64)>,
+        track: TrackResult,
+        hash_version: u8,
+        cover: Option<&Cover>,
+    ) -> Result<()> {
+        {
+            let mut map = self.hashes.write().map_err(|e| anyhow!("{}", e))?;
+            for (hash, time) in hashes {
+                map.entry(hash)
+                    .or_default()
+                    .insert((time, track.id.clone()));
+            }
+        }
+        self.versions
+            .write()
+            .map_err(|e| anyhow!("{}", e))?
+            .insert(track.id.clone(), hash_version);
+        if let Some(cover) = cover {
+            self.set_cover(&track.id, cover).await?;
+        }
+        self.set_track(track).await
+    }
+
+    async fn get_hash_versions(&self, ids: &[String]) -> Result<Vec<Option<u8>>> {
+        let versions = self.versions.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(ids.iter().map(|id| versions.get(id).copied()).collect())
+    }
+
+    async fn lookup_hashes(&self, hashes: &[u64]) -> Result<Vec<Vec<(u64, String)>>> {
+        let map = self.hashes.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(hashes
+            .iter()
+            .map(|hash| {
+                map.get(hash)
+                    .map(|entries| entries.iter().cloned().collect())
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    async fn get_track(&self, id: &str) -> Result<Option<TrackResult>> {
+        let tracks = self.tracks.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(tracks.get(id).cloned())
+    }
+
+    async fn set_track(&self, track: TrackResult) -> Result<()> {
+        let mut tracks = self.tracks.write().map_err(|e| anyhow!("{}", e))?;
+        tracks.insert(track.id.clone(), track);
+        Ok(())
+    }
+
+    async fn get_tracks(&self, ids: &[String]) -> Result<Vec<TrackResult>> {
+        let tracks = self.tracks.read().map_err(|e| anyhow!("{}", e))?;
+        ids.iter()
+            .map(|id| {
+                tracks
+                    .get(id)
+                    .cloned()
+                    .ok_or(anyhow!("No value found for key: {}", id))
+            })
+            .collect()
+    }
+
+    async fn get_all_tracks(&self) -> Result<Vec<TrackResult>> {
+        let tracks = self.tracks.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(tracks.values().cloned().collect())
+    }
+
+    async fn set_cover(&self, id: &str, cover: &Cover) -> Result<()> {
+        let mut covers = self.covers.write().map_err(|e| anyhow!("{}", e))?;
+        covers.insert(id.to_string(), cover.clone());
+        Ok(())
+    }
+
+    async fn get_cover(&self, id: &str) -> Result<Option<Cover>> {
+        let covers = self.covers.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(covers.get(id).cloned())
+    }
+
+    async fn add_alias(&self, alias: &str, id: &str) -> Result<()> {
+        let mut aliases = self.aliases.write().map_err(|e| anyhow!("{}", e))?;
+        aliases.insert(alias.to_string(), id.to_string());
+        Ok(())
+    }
+
+    async fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        let aliases = self.aliases.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(aliases.get(alias).cloned())
+    }
+
+    async fn get_config(&self) -> Result<Option<FingerprintConfig>> {
+        let config = self.config.read().map_err(|e| anyhow!("{}", e))?;
+        Ok(config.clone())
+    }
+
+    async fn set_config(&self, config: &FingerprintConfig) -> Result<()> {
+        *self.config.write().map_err(|e| anyhow!("{}", e))? = Some(config.clone());
+        Ok(())
+    }
+
+    async fn delete_song(&self, id: &str) -> Result<()> {
+        {
+            let mut map = self.hashes.write().map_err(|e| anyhow!("{}", e))?;
+            map.retain(|_, entries| {
+                entries.retain(|(_, song_id)| song_id != id);
+                !entries.is_empty()
+            });
+        }
+        self.versions
+            .write()
+            .map_err(|e| anyhow!("{}", e))?
+            .remove(id);
+        self.aliases
+            .write()
+            .map_err(|e| anyhow!("{}", e))?
+            .retain(|_, song_id| song_id != id);
+        self.covers
+            .write()
+            .map_err(|e| anyhow!("{}", e))?
+            .remove(id);
+        let mut tracks = self.tracks.write().map_err(|e| anyhow!("{}", e))?;
+        tracks.remove(id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::EmbeddedMetadata;
+
+    fn track(id: &str) -> TrackResult {
+        EmbeddedMetadata::default().to_track(id.to_string(), id.to_string(), String::new())
+    }
+
+    async fn lookup(store: &dyn FingerprintStore, hashes: &[u64]) -> Vec<Vec<(u64, String)>> {
+        let mut entries = store.lookup_hashes(hashes).await.unwrap();
+        entries.iter_mut().for_each(|entries| entries.sort());
+        entries
+    }
+
+    async fn round_trip(store: &dyn FingerprintStore) {
+        let cover = Cover {
+            media_type: "image/png".to_string(),
+            data: vec![0x89, b'P', b'N', b'G'],
+        };
+        store
+            .insert_song(
+                vec![(1, 100), (2, 200), (1, 300)],
+                track("a"),
+                1,
+                Some(&cover),
+            )
+            .await
+            .unwrap();
+        store
+            .insert_song(vec![(1, 150), (3, 50)], track("b"), 2, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            lookup(store, &[1, 2, 3, 4]).await,
+            vec![
+                vec![
+                    (100, "a".to_string()),
+                    (150, "b".to_string()),
+                    (300, "a".to_string())
+                ],
+                vec![(200, "a".to_string())],
+                vec![(50, "b".to_string())],
+                vec![],
+            ]
+        );
+        let ids = ["a", "b", "c"].map(String::from);
+        assert_eq!(
+            store.get_hash_versions(&ids).await.unwrap(),
+            vec![Some(1), Some(2), None]
+        );
+        assert_eq!(store.get_track("a").await.unwrap().unwrap().id, "a");
+        assert!(store.get_track("c").await.unwrap().is_none());
+
+        assert_eq!(store.get_cover("a").await.unwrap(), Some(cover.clone()));
+        assert!(store.get_cover("b").await.unwrap().is_none());
+        store.set_cover("b", &cover).await.unwrap();
+        assert_eq!(store.get_cover("b").await.unwrap(), Some(cover));
+
+        store.add_alias("a-elsewhere", "a").await.unwrap();
+        assert_eq!(
+            store.resolve_alias("a-elsewhere").await.unwrap().as_deref(),
+            Some("a")
+        );
+        assert!(store.resolve_alias("a").await.unwrap().is_none());
+
+        store.delete_song("a").await.unwrap();
+        assert_eq!(
+            lookup(store, &[1, 2, 3]).await,
+            vec![
+                vec![(150, "b".to_string())],
+                vec![],
+                vec![(50, "b".to_string())],
+            ]
+        );
+        assert!(store.get_track("a").await.unwrap().is_none());
+        assert!(store.get_cover("a").await.unwrap().is_none());
+        assert_eq!(
+            store.get_hash_versions(&ids).await.unwrap(),
+            vec![None, Some(2), None]
+        );
+        assert!(store.resolve_alias("a-elsewhere").await.unwrap().is_none());
+        let tracks = store.get_all_tracks().await.unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].id, "b");
+    }
+
+    /// Empty sled database in the temp dir, removed when dropped.
+    struct TempSled(std::path::PathBuf);
+
+    impl TempSled {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("sonic-scan-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempSled {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_store_round_trip() {
+        round_trip(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sled_store_round_trip() {
+        let dir = TempSled::new("sled-round-trip");
+        round_trip(&SledStore::open(&dir.0).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sled_delete_unindexed_song() {
+        let dir = TempSled::new("sled-unindexed");
+        let store = SledStore::open(&dir.0).unwrap();
+        store
+            .insert_song(vec![(1, 100), (2, 200)], track("old"), 1, None)
+            .await
+            .unwrap();
+        store
+            .insert_song(vec![(1, 50)], track("new"), 1, None)
+            .await
+            .unwrap();
+        // as left by a version without the reverse index
+        store.song_hashes.clear().unwrap();
+        store.meta.remove(REVERSE_INDEX_KEY).unwrap();
+
+        store.delete_song("old").await.unwrap();
+        assert_eq!(
+            lookup(&store, &[1, 2]).await,
+            vec![vec![(50, "new".to_string())], vec![]]
+        );
+
+        assert_eq!(store.rebuild_index().await.unwrap(), 1);
+        store.delete_song("new").await.unwrap();
+        assert_eq!(lookup(&store, &[1]).await, vec![vec![]]);
+    }
+
+    #[tokio::test]
+    async fn config_round_trip() {
+        let dir = TempSled::new("sled-config");
+        let store = SledStore::open(&dir.0).unwrap();
+        let config = FingerprintConfig::default();
+        assert!(store.get_config().await.unwrap().is_none());
+        ensure_config(&store, &config).await.unwrap();
+        assert_eq!(store.get_config().await.unwrap(), Some(config));
+    }
+
+    #[tokio::test]
+    async fn old_pipeline_version_requires_reingest() {
+        let store = MemoryStore::default();
+        let old = FingerprintConfig {
+            version: PIPELINE_VERSION - 1,
+            ..FingerprintConfig::default()
+        };
+        store.set_config(&old).await.unwrap();
+        let config = FingerprintConfig::default();
+        for error in [
+            check_config(&store, &config).await.unwrap_err(),
+            ensure_config(&store, &config).await.unwrap_err(),
+        ] {
+            assert!(error.to_string().contains("pipeline version"));
+        }
+        // a config claiming the old version does not get around it
+        assert!(check_config(&store, &old).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_config_requires_reingest() {
+        let store = MemoryStore::default();
+        ensure_config(&store, &FingerprintConfig::default())
+            .await
+            .unwrap();
+        let config = FingerprintConfig {
+            fan_out: FingerprintConfig::default().fan_out + 1,
+            ..FingerprintConfig::default()
+        };
+        let error = check_config(&store, &config).await.unwrap_err();
+        assert!(error.to_string().contains("re-ingest required"));
+        assert!(ensure_config(&store, &config).await.is_err());
+        assert_eq!(
+            store.get_config().await.unwrap(),
+            Some(FingerprintConfig::default())
+        );
+    }
+}
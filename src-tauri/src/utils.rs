@@ -1,9 +1,6 @@
 #![allow(unused)]
 
 use anyhow::Result;
-use kdtree::KdTree;
-use kdtree::distance::squared_euclidean;
-use realfft::{RealFftPlanner, num_complex::Complex};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 use std::{
@@ -12,11 +9,11 @@ use std::{
 use bincode::{serde::{encode_to_vec, decode_from_slice}, Decode, Encode, config::standard};
 use symphonia::{
     core::{
-        audio::{AudioBuffer, AudioBufferRef, Signal},
-        codecs::{CODEC_TYPE_NULL, DecoderOptions},
+        audio::{AudioBuffer, AudioBufferRef, SampleBuffer, Signal},
+        codecs::{CODEC_TYPE_NULL, Decoder, DecoderOptions},
         conv::IntoSample,
         errors::Error as SError,
-        formats::FormatOptions,
+        formats::{FormatOptions, FormatReader},
         io::MediaSourceStream,
         meta::MetadataOptions,
         probe::Hint,
@@ -25,6 +22,9 @@ use symphonia::{
 };
 use hound::WavReader;
 
+use crate::fingerprint::FingerprintConfig;
+use crate::hash::{encode_hash, encode_ratio_hash};
+
 pub const BANDS: [(usize, usize); 10] = [
     (0, 32),
     (32, 64),
@@ -52,7 +52,7 @@ pub const ANCHOR_POINTS: usize = 5;
 pub const NUM_BINS: usize = 2048;
 pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
 
-pub(crate) fn open_file(path: &str) -> Result<MediaSourceStream> {
+pub(crate) fn open_file(path: impl AsRef<Path>) -> Result<MediaSourceStream> {
     let file = Box::new(File::open(path)?);
     Ok(MediaSourceStream::new(file, Default::default()))
 }
@@ -62,113 +62,193 @@ pub(crate) fn open_binary(buf: Vec<u8>) -> Result<MediaSourceStream> {
     Ok(MediaSourceStream::new(file, Default::default()))
 }
 
-pub(crate) fn extract_mono_audio(mss: MediaSourceStream) -> Result<(Vec<f32>, u32)> {
-    let probe = get_probe().format(&Hint::new(), mss, &Default::default(), &Default::default())?;
-    let mut format = probe.format;
+/// Outcome of decoding a file, packets the decoder rejected are skipped instead of failing.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodeReport {
+    pub packets_decoded: usize,
+    pub packets_skipped: usize,
+    pub decoder_resets: usize,
+    /// Duration of the decoded audio in seconds.
+    pub duration_secs: f64,
+}
 
-    let track = format
-        .default_track()
-        .ok_or(anyhow::anyhow!("No audio track found"))?;
-    let track_id = track.id;
-    
-    let mut decoder = get_codecs().make(&track.codec_params, &Default::default())
-        .map_err    (|e| anyhow::anyhow!("Failed to create decoder: {e}"))?;
-    let sample_rate = track
-        .codec_params
-        .sample_rate
-        .unwrap_or(DEFAULT_SAMPLE_RATE);
+/// Packet by packet decoder producing mono samples, so callers can process a
+/// file without holding all of it in memory.
+pub(crate) struct MonoDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    samples: Vec<f32>,
+    pcm: Vec<i16>,
+    decoded: u64,
+    report: DecodeReport,
+}
 
-    let mut all_samples: Vec<f32> = vec![];
+impl MonoDecoder {
+    pub fn new(mss: MediaSourceStream) -> Result<Self> {
+        let probe = get_probe().format(&Hint::new(), mss, &Default::default(), &Default::default())?;
+        Self::from_format(probe.format)
+    }
 
-    while let Ok(packet) = format.next_packet() {
-        if packet.track_id() != track_id {
-            continue;
-        }
-        match decoder.decode(&packet) {
-            Ok(decoded) => {
-                match decoded {
-                    AudioBufferRef::S16(buf) => {
-                        let buf_i16 = buf.into_owned();
-                        for frame in 0..buf_i16.frames() {
-                            // Downmix to mono
-                            let mut sample: i32 = 0;
-                            for chan in 0..buf_i16.spec().channels.count() {
-                                sample += buf_i16.chan(chan)[frame] as i32;
-                            }
-                            sample /= buf_i16.spec().channels.count() as i32;
-                            all_samples.push(sample as f32 / i16::MAX as f32);
-                        }
-                    }
-                    AudioBufferRef::F32(buf) => {
-                        let buf_f32 = buf.into_owned();
-                        for frame in 0..buf_f32.frames() {
-                            // Downmix to mono
-                            let mut sample: f64 = 0.0;
-                            for chan in 0..buf_f32.spec().channels.count() {
-                                sample += buf_f32.chan(chan)[frame] as f64;
-                            }
-                            sample /= buf_f32.spec().channels.count() as f64;
-                            all_samples.push(sample as f32);
-                        }
-                    }
-                    _ => return Err(anyhow::anyhow!("Unsupported audio format")),
+    /// Decodes the default track of a stream that has already been probed.
+    pub fn from_format(format: Box<dyn FormatReader>) -> Result<Self> {
+        let track = format
+            .default_track()
+            .ok_or(anyhow::anyhow!("No audio track found"))?;
+        let track_id = track.id;
+
+        let decoder = get_codecs()
+            .make(&track.codec_params, &Default::default())
+            .map_err(|e| anyhow::anyhow!("Failed to create decoder: {e}"))?;
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .unwrap_or(DEFAULT_SAMPLE_RATE);
+
+        Ok(Self {
+            format,
+            decoder,
+            track_id,
+            sample_rate,
+            samples: vec![],
+            pcm: vec![],
+            decoded: 0,
+            report: DecodeReport::default(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Decodes up to the next good packet, returns `None` at the end of the stream.
+    pub fn next_chunk(&mut self) -> Result<Option<&[f32]>> {
+        let mut samples = std::mem::take(&mut self.samples);
+        samples.clear();
+        let decoded = self.decode_next(|decoded| push_mono(decoded, &mut samples))?;
+        self.samples = samples;
+        Ok(decoded.then_some(&self.samples[..]))
+    }
+
+    /// [`MonoDecoder::next_chunk`] as 16 bit PCM, with the channels averaged in
+    /// integers and rounded towards zero the way Chromaprint downmixes.
+    pub fn next_pcm_chunk(&mut self) -> Result<Option<&[i16]>> {
+        let mut pcm = std::mem::take(&mut self.pcm);
+        pcm.clear();
+        let decoded = self.decode_next(|decoded| push_pcm_mono(decoded, &mut pcm))?;
+        self.pcm = pcm;
+        Ok(decoded.then_some(&self.pcm[..]))
+    }
+
+    /// Hands the next good packet to `downmix`, returns `false` at the end of the stream.
+    fn decode_next(&mut self, downmix: impl FnOnce(AudioBufferRef)) -> Result<bool> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                // the format reader signals the end of the stream with an EOF io error
+                Err(SError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(SError::ResetRequired) => {
+                    self.reset()?;
+                    continue;
+                }
+                Err(e) => return Err(anyhow::anyhow!("Failed to read packet: {e}")),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.decoded += decoded.frames() as u64;
+                    self.report.packets_decoded += 1;
+                    downmix(decoded);
+                    return Ok(true);
+                }
+                Err(SError::DecodeError(_)) | Err(SError::IoError(_)) => {
+                    self.report.packets_skipped += 1
                 }
+                Err(SError::ResetRequired) => self.reset()?,
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
         }
     }
-    
-    Ok((all_samples, sample_rate))
+
+    /// Switches to a fresh decoder for the default track after the stream asked
+    /// for a reset, fails if the new track has another sample rate since the
+    /// samples already returned were at the old one.
+    fn reset(&mut self) -> Result<()> {
+        let (track_id, decoder, sample_rate) = recreate_decoder(self.format.as_ref())?;
+        if sample_rate.is_some_and(|sr| sr != self.sample_rate) {
+            return Err(anyhow::anyhow!(
+                "Sample rate changed from {} Hz to {} Hz mid-stream",
+                self.sample_rate,
+                sample_rate.unwrap_or_default()
+            ));
+        }
+        (self.track_id, self.decoder) = (track_id, decoder);
+        self.report.decoder_resets += 1;
+        Ok(())
+    }
+
+    /// Report of the whole decode, fails if not a single packet could be decoded.
+    pub fn finish(self) -> Result<DecodeReport> {
+        let mut report = self.report;
+        if report.packets_decoded == 0 {
+            return Err(anyhow::anyhow!("No decodable audio packets found"));
+        }
+        report.duration_secs = self.decoded as f64 / self.sample_rate as f64;
+        Ok(report)
+    }
 }
 
-pub(crate) fn downsample(mut audio: Vec<f32>, sr: u32, d: usize) -> (Vec<f32>, u32) {
-    (
-        audio
-            .chunks(d)
-            .map(|chunk| chunk.iter().sum::<f32>() / d as f32)
-            .collect(),
-        sr / d as u32,
-    )
+pub(crate) fn extract_mono_audio(mss: MediaSourceStream) -> Result<(Vec<f32>, u32, DecodeReport)> {
+    let mut decoder = MonoDecoder::new(mss)?;
+    let mut all_samples: Vec<f32> = vec![];
+    while let Some(samples) = decoder.next_chunk()? {
+        all_samples.extend_from_slice(samples);
+    }
+    let sample_rate = decoder.sample_rate();
+    Ok((all_samples, sample_rate, decoder.finish()?))
 }
 
-pub(crate) fn normalise(audio: Vec<f32>) -> Vec<f32> {
-    let max = audio.iter().fold(f32::MIN, |max, &x| f32::max(max, x));
-    audio.iter().map(|&x| x / max).collect()
+/// Builds a fresh decoder for the default track after the stream asked for a reset,
+/// with the sample rate of the track if it has one.
+fn recreate_decoder(format: &dyn FormatReader) -> Result<(u32, Box<dyn Decoder>, Option<u32>)> {
+    let track = format
+        .default_track()
+        .ok_or(anyhow::anyhow!("No audio track found after reset"))?;
+    let decoder = get_codecs()
+        .make(&track.codec_params, &Default::default())
+        .map_err(|e| anyhow::anyhow!("Failed to recreate decoder: {e}"))?;
+    Ok((track.id, decoder, track.codec_params.sample_rate))
 }
 
-pub fn hann_function(n: usize, samples: usize) -> f32 {
-    0.5 * (1.0 - f32::cos((2.0 * std::f32::consts::PI * n as f32) / (samples as f32 - 1.0)))
+/// Converts a decoded buffer of any sample format to `f32` and appends its mono downmix.
+fn push_mono(decoded: AudioBufferRef, samples: &mut Vec<f32>) {
+    let channels = decoded.spec().channels.count();
+    let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+    buf.copy_interleaved_ref(decoded);
+    samples.extend(
+        buf.samples()
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+    );
 }
 
-pub(crate) fn stft(mut audio: Vec<f32>, fft_size: usize, hop_size: usize) -> Result<Vec<Vec<f32>>> {
-    let n = audio.len();
-
-    // compute FFT
-    let mut planner = RealFftPlanner::<f32>::new();
-    let r2c = planner.plan_fft_forward(fft_size);
-
-    let window: Vec<f32> = (0..fft_size).map(|n| hann_function(n, fft_size)).collect();
-
-    // STFT loop
-    let mut stft_result = Vec::new();
-    let mut frame = 0;
-    while frame + fft_size <= n {
-        // Windowed frame
-        audio[frame..frame + fft_size]
-            .iter_mut()
-            .zip(&window)
-            .for_each(|(x, &w)| *x *= w);
-        // FFT
-        let mut buf = r2c.make_output_vec();
-
-        r2c.process(&mut audio[frame..frame + fft_size], &mut buf)
-            .unwrap();
-        let buf = buf.iter().map(|c| c.norm()).collect();
-        stft_result.push(buf);
-        frame += hop_size;
-    }
+fn push_pcm_mono(decoded: AudioBufferRef, pcm: &mut Vec<i16>) {
+    let channels = decoded.spec().channels.count();
+    let mut buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+    buf.copy_interleaved_ref(decoded);
+    pcm.extend(buf.samples().chunks(channels).map(|frame| {
+        (frame.iter().map(|&x| x as i32).sum::<i32>() / channels as i32) as i16
+    }));
+}
 
-    Ok(stft_result)
+pub fn hann_function(n: usize, samples: usize) -> f32 {
+    0.5 * (1.0 - f32::cos((2.0 * std::f32::consts::PI * n as f32) / (samples as f32 - 1.0)))
 }
 
 pub(crate) fn to_db(spec: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
@@ -237,88 +317,218 @@ pub(crate) fn save_spectrogram_image(name: &str, db_spec: &Vec<Vec<f32>>) -> Res
 //     Ok(spec.compute())
 // }
 
-pub(crate) fn filter_stft(
-    stft_result: Vec<Vec<f32>>,
+/// Start of the `i`th spectrogram frame in milliseconds.
+pub(crate) fn frame_time(i: usize, sr: usize, hop_size: usize) -> usize {
+    ((i as f32 * hop_size as f32) / sr as f32 * 1000.0).floor() as usize
+}
+
+/// Peaks of the `i`th spectrogram frame as `(time ms, freq Hz, magnitude)`, the loudest
+/// bin of every band above the threshold, keeping only those above the frame average.
+/// `freqs` holds the frequency of every bin of the frame and `bands` the band edges in Hz.
+pub(crate) fn frame_peaks(
+    frame: &[f32],
+    i: usize,
     sr: usize,
-    fft_size: usize,
-    hop_size: usize,
+    freqs: &[usize],
+    bands: &[(usize, usize)],
+    config: &FingerprintConfig,
 ) -> Vec<(usize, usize, f32)> {
-    let filtered_stft = stft_result
+    let time = frame_time(i, sr, config.hop_size);
+    let peaks = bands
         .iter()
-        .enumerate()
-        .map(|(i, frame)| {
-            BANDS
+        .map(|(min, max)| {
+            frame
                 .iter()
-                .map(|(min, max)| {
-                    frame
-                        .iter()
-                        .enumerate()
-                        .filter(|(j, _)| {
-                            let freq = j * sr as usize / fft_size;
-                            freq >= *min && freq < *max
-                        })
-                        .fold((0usize, 0usize, 0.0f32), |(time_, freq_, acc), (j, &x)| {
-                            let time = ((i as f32 * hop_size as f32) / sr as f32 * 1000.0).floor() as usize;
-                            let freq = j * sr as usize / fft_size;
-                            if acc < x {
-                                (time, freq, x)
-                            } else {
-                                (time_, freq_, acc)
-                            }
-                        })
+                .zip(freqs)
+                .filter(|&(_, &freq)| freq >= *min && freq < *max)
+                .fold((time, 0usize, 0.0f32), |(time, freq_, acc), (&x, &freq)| {
+                    if acc < x {
+                        (time, freq, x)
+                    } else {
+                        (time, freq_, acc)
+                    }
                 })
-                .filter(|&(_, _, x)| x > 1.0)
-                .collect::<Vec<(usize, usize, f32)>>()
         })
-        .filter(|v| !v.is_empty())
-        .collect::<Vec<Vec<(usize, usize, f32)>>>();
+        .filter(|&(_, _, x)| x > config.peak_threshold)
+        .collect::<Vec<(usize, usize, f32)>>();
 
     // filter out basic noise
-    filtered_stft
+    let avg = peaks.iter().map(|&(_, _, x)| x).sum::<f32>() / peaks.len() as f32;
+    peaks.into_iter().filter(|&(_, _, x)| x > avg).collect()
+}
+
+pub(crate) fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+pub(crate) fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// `n_mels + 2` frequencies in Hz evenly spaced on the mel scale from `fmin` to `fmax`,
+/// filter `m` rises from point `m` to its peak at `m + 1` and falls back to zero at `m + 2`.
+pub(crate) fn mel_points(n_mels: usize, fmin: f32, fmax: f32) -> Vec<f32> {
+    let mel_min = hz_to_mel(fmin);
+    let mel_max = hz_to_mel(fmax);
+    (0..n_mels + 2)
+        .map(|i| mel_to_hz(mel_min + (mel_max - mel_min) * i as f32 / (n_mels + 1) as f32))
+        .collect()
+}
+
+/// Triangular mel filters over the `n_fft / 2 + 1` bins of a spectrum frame.
+pub(crate) fn mel_filterbank(
+    sr: u32,
+    n_fft: usize,
+    n_mels: usize,
+    fmin: f32,
+    fmax: f32,
+) -> Vec<Vec<f32>> {
+    let mut filters = vec![vec![0.0; n_fft / 2 + 1]; n_mels];
+    let hz_points = mel_points(n_mels, fmin, fmax);
+    let bin = |freq: f32| (((n_fft + 1) as f32 * freq / sr as f32).floor() as usize).min(n_fft / 2);
+
+    for m in 1..=n_mels {
+        let f_m_minus = bin(hz_points[m - 1]);
+        let f_m = bin(hz_points[m]);
+        let f_m_plus = bin(hz_points[m + 1]);
+        for k in f_m_minus..f_m {
+            filters[m - 1][k] = (k - f_m_minus) as f32 / (f_m - f_m_minus) as f32;
+        }
+        for k in f_m..f_m_plus {
+            filters[m - 1][k] = (f_m_plus - k) as f32 / (f_m_plus - f_m) as f32;
+        }
+    }
+    filters
+}
+
+/// Applies a filterbank to one spectrum frame.
+pub(crate) fn apply_filterbank(frame: &[f32], filters: &[Vec<f32>]) -> Vec<f32> {
+    filters
+        .iter()
+        .map(|filter| frame.iter().zip(filter).map(|(x, w)| x * w).sum())
+        .collect()
+}
+
+/// Hashes pairing `anchor` with its `fan_out` nearest peaks in (ms, Hz) space among `window`,
+/// which must not contain the anchor itself.
+pub(crate) fn nearest_hashes<'a>(
+    anchor: &(usize, usize, f32),
+    window: impl Iterator<Item = &'a (usize, usize, f32)>,
+    sr: usize,
+    config: &FingerprintConfig,
+) -> Vec<u64> {
+    let (fft_size, hop_size) = (config.fft_size, config.hop_size);
+    let &(time, freq, _) = anchor;
+    let (frame, bin) = (
+        time_to_frame(time, sr, hop_size),
+        freq_to_bin(freq, sr, fft_size),
+    );
+    let mut nearest = window
+        .map(|&(t, f, _)| {
+            let (dt, df) = (t as f32 - time as f32, f as f32 - freq as f32);
+            (dt * dt + df * df, t, f)
+        })
+        .collect::<Vec<(f32, usize, usize)>>();
+    nearest.sort_by(|a, b| a.0.total_cmp(&b.0));
+    nearest
         .into_iter()
-        .map(|frame| {
-            let avg = frame.iter().map(|&(_, _, x)| x).sum::<f32>() / frame.len() as f32;
-            frame
-                .into_iter()
-                .filter(|&(_, _, x)| x > avg)
-                .collect::<Vec<(usize, usize, f32)>>()
+        .take(config.fan_out)
+        .map(|(_, t, f)| {
+            encode_hash(
+                bin,
+                freq_to_bin(f, sr, fft_size),
+                time_to_frame(t, sr, hop_size).abs_diff(frame),
+            )
         })
-        .filter(|v| !v.is_empty())
-        .flatten()
         .collect()
 }
 
-pub fn hash_tuple(freq1: usize, freq2: usize, time: u64) -> u64 {
-    let mut hash = (freq1 as u64) << 48;
-    hash += ((freq2 as u64) & 0xFFFF) << 16;
-    hash |= time & 0xFFFFFFFF;
-    hash
+/// Hashes pairing `anchor` with the `fan_out` loudest of the `later` peaks inside its target
+/// zone of `(delay, length, height)` in frames and bins, with frequencies as bin indices
+/// and the time delta in frames.
+pub(crate) fn target_zone_hashes<'a>(
+    anchor: &(usize, usize, f32),
+    later: impl Iterator<Item = &'a (usize, usize, f32)>,
+    zone: (usize, usize, usize),
+    sr: usize,
+    config: &FingerprintConfig,
+) -> Vec<u64> {
+    let bin = freq_to_bin(anchor.1, sr, config.fft_size);
+    zone_targets(anchor, later, zone, sr, config)
+        .into_iter()
+        .map(|(dt, b)| encode_hash(bin, b, dt))
+        .collect()
 }
 
-pub(crate) fn filter_to_data(
-    data: Vec<(usize, usize, f32)>,
-    song_id: &str,
-) -> Result<HashMap<u64, (u64, &str)>> {
-    // Suppose your points are Vec<[f32; 2]> for 2D
-    let mut tree = KdTree::new(2);
-    for (i, (time, freq, _)) in data.iter().enumerate() {
-        tree.add([*time as f32, *freq as f32], i)?;
+/// Ratio hashes of every triplet of `anchor` and two of its target zone peaks picked
+/// like [`target_zone_hashes`], see [`encode_ratio_hash`].
+pub(crate) fn triplet_hashes<'a>(
+    anchor: &(usize, usize, f32),
+    later: impl Iterator<Item = &'a (usize, usize, f32)>,
+    zone: (usize, usize, usize),
+    sr: usize,
+    config: &FingerprintConfig,
+) -> Vec<u64> {
+    let bin = freq_to_bin(anchor.1, sr, config.fft_size);
+    if bin == 0 {
+        return vec![];
     }
-
-    let mut points = HashMap::new();
-    for (time, freq, x) in data.iter() {
-        // Find 5 because the point itself will be included as the nearest
-        let nearest = tree.nearest(&[*time as f32, *freq as f32], ANCHOR_POINTS, &squared_euclidean)?;
-        // Filter out the point itself if needed
-        nearest.into_iter().skip(1).for_each(|(_, i)| {
-            points.insert(
-                hash_tuple(*freq, data[*i].1, (*time as i64 - data[*i].0 as i64).abs() as u64),
-                (*time as u64, song_id),
-            );
-        });
+    let mut targets = zone_targets(anchor, later, zone, sr, config);
+    targets.retain(|&(_, b)| b > 0);
+    targets.sort_unstable();
+
+    let mut hashes = vec![];
+    for (i, &(dt_b, b)) in targets.iter().enumerate() {
+        for &(dt_c, c) in &targets[i + 1..] {
+            if dt_b < dt_c {
+                hashes.push(encode_ratio_hash(bin, (b, c), (dt_b, dt_c)));
+            }
+        }
     }
+    hashes
+}
 
-    Ok(points)
+/// The `fan_out` loudest of the `later` peaks inside the target zone of `anchor`,
+/// as `(frames after the anchor, bin)`.
+fn zone_targets<'a>(
+    anchor: &(usize, usize, f32),
+    later: impl Iterator<Item = &'a (usize, usize, f32)>,
+    (delay, length, height): (usize, usize, usize),
+    sr: usize,
+    config: &FingerprintConfig,
+) -> Vec<(usize, usize)> {
+    let (fft_size, hop_size) = (config.fft_size, config.hop_size);
+    let (frame, bin) = (
+        time_to_frame(anchor.0, sr, hop_size),
+        freq_to_bin(anchor.1, sr, fft_size),
+    );
+    let mut targets = later
+        .map(|&(t, f, x)| {
+            (
+                time_to_frame(t, sr, hop_size).saturating_sub(frame),
+                freq_to_bin(f, sr, fft_size),
+                x,
+            )
+        })
+        .take_while(|&(dt, _, _)| dt < delay + length)
+        .filter(|&(dt, b, _)| dt >= delay && b.abs_diff(bin) <= height)
+        .collect::<Vec<(usize, usize, f32)>>();
+    targets.sort_by(|a, b| b.2.total_cmp(&a.2));
+    targets
+        .into_iter()
+        .take(config.fan_out)
+        .map(|(dt, b, _)| (dt, b))
+        .collect()
+}
+
+/// Frame index of a peak time given in milliseconds by [`frame_time`].
+pub(crate) fn time_to_frame(time: usize, sr: usize, hop_size: usize) -> usize {
+    (time as f64 * sr as f64 / (hop_size as f64 * 1000.0)).round() as usize
+}
+
+/// Bin index of a peak frequency given in Hz by [`frame_peaks`].
+pub(crate) fn freq_to_bin(freq: usize, sr: usize, fft_size: usize) -> usize {
+    (freq * fft_size).div_ceil(sr)
 }
 
 // function to save Vec<f32> to wav
@@ -338,10 +548,10 @@ pub(crate) fn filter_to_data(
 //     Ok(())
 // }
 
-#[derive(Debug, Serialize, Deserialize, Encode, Decode)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Encode, Decode)]
 pub(crate) struct Data {
-    time: u64,
-    song_id: String,
+    pub time: u64,
+    pub song_id: String,
 }
 
 //  9597952
@@ -441,61 +651,154 @@ pub(crate) struct Data {
 //     }
 // }
 
-// fn hz_to_mel(hz: f32) -> f32 {
-//     2595.0 * (1.0 + hz / 700.0).log10()
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::FrequencyScale;
+
+    /// Stereo frames as `(left, right)` in `-1.0..1.0`, exactly representable at every bit depth.
+    const FRAMES: [(f32, f32); 4] = [(0.5, -0.25), (0.0, 0.0), (-1.0, 0.75), (0.25, 0.25)];
+
+    /// Encodes `FRAMES` as a WAV with `channels` channels, the mono one only has the left samples.
+    fn wav(bits_per_sample: u16, sample_format: hound::SampleFormat, channels: u16) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: 22050,
+            bits_per_sample,
+            sample_format,
+        };
+        let mut wav = Cursor::new(vec![]);
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for (left, right) in FRAMES {
+            for &x in [left, right].iter().take(channels as usize) {
+                match (sample_format, bits_per_sample) {
+                    (hound::SampleFormat::Float, _) => writer.write_sample(x).unwrap(),
+                    (_, 8) => writer.write_sample((x * 128.0) as i8).unwrap(),
+                    (_, 16) => writer.write_sample((x * 32768.0) as i16).unwrap(),
+                    (_, bits) => writer
+                        .write_sample((x as f64 * (1i64 << (bits - 1)) as f64) as i32)
+                        .unwrap(),
+                }
+            }
+        }
+        writer.finalize().unwrap();
+        wav.into_inner()
+    }
 
-// fn mel_to_hz(mel: f32) -> f32 {
-//     700.0 * (10f32.powf(mel / 2595.0) - 1.0)
-// }
+    fn decode(wav: Vec<u8>) -> Vec<f32> {
+        let (samples, sr, report) = extract_mono_audio(open_binary(wav).unwrap()).unwrap();
+        assert_eq!(sr, 22050);
+        assert_eq!(report.packets_skipped, 0);
+        samples
+    }
 
-// fn mel_filterbank(
-//     sr: u32,
-//     n_fft: usize,
-//     n_mels: usize,
-//     fmin: f32,
-//     fmax: f32,
-// ) -> Vec<Vec<f32>> {
-//     let mut filters = vec![vec![0.0; n_fft / 2 + 1]; n_mels];
-//     let mel_min = hz_to_mel(fmin);
-//     let mel_max = hz_to_mel(fmax);
-//     let mel_points: Vec<f32> = (0..n_mels + 2)
-//         .map(|i| mel_min + (mel_max - mel_min) * i as f32 / (n_mels + 1) as f32)
-//         .collect();
-//     println!("Mel points: {:?}", mel_points);
-//     let hz_points: Vec<f32> = mel_points.into_iter().map(mel_to_hz).collect();
-//     println!("Hz points: {:?}", hz_points);
-//     let bin = |freq: f32| ((n_fft + 1) as f32 * freq / sr as f32).floor() as usize;
-
-//     for m in 1..=n_mels {
-//         let f_m_minus = bin(hz_points[m - 1]);
-//         let f_m = bin(hz_points[m]);
-//         let f_m_plus = bin(hz_points[m + 1]);
-//         for k in f_m_minus..f_m {
-//             filters[m - 1][k] = (k - f_m_minus) as f32 / (f_m - f_m_minus) as f32;
-//         }
-//         for k in f_m..f_m_plus {
-//             filters[m - 1][k] = (f_m_plus - k) as f32 / (f_m_plus - f_m) as f32;
-//         }
-//     }
-//     filters
-// }
+    #[test]
+    fn decodes_every_bit_depth() {
+        for (bits, format) in [
+            (8, hound::SampleFormat::Int),
+            (16, hound::SampleFormat::Int),
+            (24, hound::SampleFormat::Int),
+            (32, hound::SampleFormat::Int),
+            (32, hound::SampleFormat::Float),
+        ] {
+            let mono = decode(wav(bits, format, 1));
+            let expected = FRAMES.map(|(left, _)| left);
+            assert_eq!(mono, expected, "{bits} bit {format:?} mono");
+
+            let stereo = decode(wav(bits, format, 2));
+            let expected = FRAMES.map(|(left, right)| (left + right) / 2.0);
+            assert_eq!(stereo, expected, "{bits} bit {format:?} stereo");
+        }
+    }
 
-// fn mel_spectrogram(
-//     stft: &[Vec<f32>],
-//     sr: u32,
-//     n_fft: usize,
-//     n_mels: usize,
-//     fmin: f32,
-//     fmax: f32,
-// ) -> Vec<Vec<f32>> {
-//     let filters = mel_filterbank(sr, n_fft, n_mels, fmin, fmax);
-//     stft.iter()
-//         .map(|frame| {
-//             filters
-//                 .iter()
-//                 .map(|filter| frame.iter().zip(filter).map(|(x, w)| x * w).sum())
-//                 .collect()
-//         })
-//         .collect()
-// }
+    #[test]
+    fn mel_scale_round_trips() {
+        for hz in [0.0, 20.0, 300.0, 1000.0, 4000.0, 11025.0, 20000.0] {
+            assert!(
+                (mel_to_hz(hz_to_mel(hz)) - hz).abs() <= hz * 1e-5 + 1e-3,
+                "{hz} Hz"
+            );
+        }
+        for mel in [0.0, 100.0, 1000.0, 2500.0] {
+            assert!(
+                (hz_to_mel(mel_to_hz(mel)) - mel).abs() <= mel * 1e-5 + 1e-3,
+                "{mel} mel"
+            );
+        }
+        // 1000 Hz is 1000 mel by construction of the scale
+        assert!((hz_to_mel(1000.0) - 1000.0).abs() < 0.1);
+    }
+
+    /// Band edges and FFT bins of 10 filters from 300 to 8000 Hz at 16 kHz with a
+    /// 512 point FFT, from the worked example of the usual MFCC tutorial.
+    const EDGES_HZ: [f32; 12] = [
+        300.0, 517.33, 781.90, 1103.97, 1496.04, 1973.32, 2554.33, 3261.62, 4122.63, 5170.76,
+        6446.70, 8000.0,
+    ];
+    const EDGE_BINS: [usize; 12] = [9, 16, 25, 35, 47, 63, 81, 104, 132, 165, 206, 256];
+
+    #[test]
+    fn mel_points_match_reference() {
+        let points = mel_points(10, 300.0, 8000.0);
+        assert_eq!(points.len(), EDGES_HZ.len());
+        for (point, expected) in points.iter().zip(EDGES_HZ) {
+            assert!(
+                (point - expected).abs() < 0.1,
+                "{point} Hz, expected {expected} Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn mel_filters_peak_at_their_centre() {
+        let filters = mel_filterbank(16000, 512, 10, 300.0, 8000.0);
+        assert_eq!(filters.len(), 10);
+        for (m, filter) in filters.iter().enumerate() {
+            let (lower, centre, upper) = (EDGE_BINS[m], EDGE_BINS[m + 1], EDGE_BINS[m + 2]);
+            assert_eq!(filter.len(), 257);
+            assert_eq!(filter[centre], 1.0, "filter {m}");
+            // the triangle is zero at both edges and positive strictly between them
+            let nonzero = filter
+                .iter()
+                .enumerate()
+                .filter(|&(_, &w)| w > 0.0)
+                .map(|(k, _)| k)
+                .collect::<Vec<usize>>();
+            assert_eq!(
+                nonzero,
+                (lower + 1..upper).collect::<Vec<usize>>(),
+                "filter {m}"
+            );
+            assert!(filter[lower + 1..centre].is_sorted(), "filter {m}");
+            assert!(
+                filter[centre..upper].is_sorted_by(|a, b| a >= b),
+                "filter {m}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_mel_scale_has_no_empty_filters() {
+        let FrequencyScale::Mel {
+            filters,
+            min_freq,
+            max_freq,
+            ..
+        } = FrequencyScale::mel()
+        else {
+            unreachable!()
+        };
+        let config = FingerprintConfig::default();
+        let bank = mel_filterbank(
+            config.target_sample_rate,
+            config.fft_size,
+            filters,
+            min_freq,
+            max_freq,
+        );
+        assert_eq!(bank.len(), filters);
+        for (m, filter) in bank.iter().enumerate() {
+            assert!(filter.iter().any(|&w| w > 0.0), "filter {m} is empty");
+        }
+    }
+}
@@ -3,15 +3,25 @@ import {
   createResource,
   createSignal,
   For,
+  onCleanup,
   onMount,
   Show,
 } from "solid-js";
 import { A } from "@solidjs/router";
 import { themeChange } from "theme-change";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { MediaRecorder, register } from "extendable-media-recorder";
 import { connect } from "extendable-media-recorder-wav-encoder";
-import { TrackResult } from "./schema";
+import {
+  CommandError,
+  ConnectionStatus,
+  StorageStatus,
+  IngestResult,
+  JobInfo,
+  MatchResult,
+  TrackResult,
+} from "./schema";
 import Fa from "solid-fa";
 import {
   faMusic,
@@ -27,6 +37,23 @@ import CloudMusic from "./assets/cloud-music.svg";
 
 import Card from "./card";
 
+const ingestMessage = (name: string, result: IngestResult) => {
+  switch (result.status) {
+    case "added":
+      return `${name} added to library`;
+    case "duplicate":
+      return `${name} is already in the library as ${result.existingId}`;
+    case "aliased":
+      return `${name} is already in the library, linked to ${result.existingId}`;
+  }
+};
+
+// storage commands reject with a CommandError, the others with a string
+const errorMessage = (error: unknown) =>
+  typeof error === "object" && error !== null && "message" in error
+    ? (error as CommandError).message
+    : `${error}`;
+
 function App() {
   const [toast, setToast] = createSignal<string | null>(null);
   const [isRecording, setIsRecording] = createSignal(false);
@@ -81,34 +108,76 @@ function App() {
         const audioBlob = new Blob(audioChunks(), { type: "audio/wav" });
         const bytes = await audioBlob.arrayBuffer();
         const audioArray = new Uint8Array(bytes);
-        const similarSongs: TrackResult[] = await invoke(
+        const result: MatchResult = await invoke(
           "similar_songs_command",
           {
             audio: Array.from(audioArray),
           },
         );
-        setSimilarSongs(similarSongs);
-        console.log(similarSongs);
-        changeToast(`Similar Songs Found`);
+        console.log(result);
+        if (result.status === "match") {
+          setSimilarSongs([result.track]);
+          const offset = Math.max(0, Math.round(result.offsetSecs));
+          const time = `${Math.floor(offset / 60)}:${String(offset % 60).padStart(2, "0")}`;
+          changeToast(
+            `Found ${result.track.name} at ${time} (${Math.round(result.confidence * 100)}% confidence)`,
+          );
+        } else {
+          setSimilarSongs([]);
+          changeToast(`No matching song found`);
+        }
         setAudioChunks([]);
       } catch (error) {
         console.error(error);
-        changeToast(`Similar Song Fetch Error: ${error}`);
+        changeToast(`Similar Song Fetch Error: ${errorMessage(error)}`);
       }
     };
     return recorder;
   };
   const [recorder] = createResource(fetchRecorder);
 
-  onMount(() => {
-    try {
-      invoke("ping_redis_command");
-    } catch (error) {
-      console.error(error);
+  let storageStatus: ConnectionStatus["status"] = "connecting";
+  let storageWarnings: string[] = [];
+  const showStorageStatus = (status: StorageStatus) => {
+    const newWarnings = status.warnings.filter((warning) =>
+      !storageWarnings.includes(warning)
+    );
+    storageWarnings = status.warnings;
+    if (newWarnings.length > 0) {
+      changeToast(newWarnings.join("\n"));
+    } else if (status.status === "unavailable") {
+      changeToast(`Storage unavailable, retrying: ${status.error}`);
+    } else if (
+      status.status === "connected" && storageStatus === "unavailable"
+    ) {
+      changeToast("Storage connected");
     }
+    storageStatus = status.status;
+  };
+
+  onMount(() => {
+    invoke<StorageStatus>("connection_status_command")
+      .then(showStorageStatus)
+      .catch(console.error);
     themeChange();
   });
 
+  const unlistenStorage = listen<StorageStatus>(
+    "storage-status",
+    (event) => showStorageStatus(event.payload),
+  );
+  onCleanup(() => unlistenStorage.then((unlisten) => unlisten()));
+
+  const unlistenJobs = listen<JobInfo>("ingest-job", (event) => {
+    const job = event.payload;
+    if (job.stage === "done") {
+      changeToast(ingestMessage(`Song ${job.label}`, job.result));
+    } else if (job.stage === "failed") {
+      changeToast(`Adding ${job.label} failed: ${job.error}`);
+    }
+  });
+  onCleanup(() => unlistenJobs.then((unlisten) => unlisten()));
+
   createEffect(() => {
     if (isRecording()) {
       recorder()?.start();
@@ -118,18 +187,24 @@ function App() {
   });
 
   const add_music_to_lib = async (song: TrackResult) => {
-    const is_present = await invoke("check_if_song_exists_command", {
-      id: song.id,
-    });
+    let is_present;
+    try {
+      is_present = await invoke("check_if_song_exists_command", {
+        id: song.id,
+      });
+    } catch (error) {
+      console.error(error);
+      changeToast(`Error: ${errorMessage(error)}`);
+      return;
+    }
     if (is_present) {
       changeToast(`Song ${song.name} is already present in library`);
       return;
     }
-    changeToast(`Adding song ${song.name} to library, please wait 1-2 minutes`);
     await invoke("add_music_to_db_command", {
       val: song,
     });
-    changeToast(`Song ${song.name} added to library`);
+    changeToast(`Adding song ${song.name} to library`);
     setAddlist((prev) => prev.filter((s) => s.id !== song.id));
   };
 
@@ -145,7 +220,7 @@ function App() {
           await invoke("add_youtube_music_to_db_command", {
             url: url,
           });
-          changeToast(`Added to database`);
+          changeToast("Adding song to library");
           return;
         } catch (error) {
           console.error(error);
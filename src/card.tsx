@@ -2,7 +2,23 @@ import { TrackResult } from "./schema";
 import { Fa } from "solid-fa";
 import { faPlus } from "@fortawesome/free-solid-svg-icons";
 import { Motion } from "solid-motionone";
-import { Show } from "solid-js";
+import { createResource, Show } from "solid-js";
+import { invoke } from "@tauri-apps/api/core";
+
+// image URL prefix of covers kept in the store instead of the track
+const COVER_URL_PREFIX = "cover:";
+const FALLBACK_IMAGE =
+    "https://upload.wikimedia.org/wikipedia/commons/thumb/b/b6/12in-Vinyl-LP-Record-Angle.jpg/500px-12in-Vinyl-LP-Record-Angle.jpg";
+
+const imageSource = async (url: string) => {
+    if (!url.startsWith(COVER_URL_PREFIX)) {
+        return url;
+    }
+    const cover = await invoke<string | null>("get_cover_command", {
+        id: url.slice(COVER_URL_PREFIX.length),
+    }).catch(() => null);
+    return cover ?? FALLBACK_IMAGE;
+};
 
 function Card({
     song,
@@ -13,9 +29,13 @@ function Card({
     add: boolean;
     add_music_to_lib: (song: TrackResult) => Promise<void>;
 }) {
-    const image_src = song.image.length > 0
-        ? song.image[song.image.length - 1].url
-        : "https://upload.wikimedia.org/wikipedia/commons/thumb/b/b6/12in-Vinyl-LP-Record-Angle.jpg/500px-12in-Vinyl-LP-Record-Angle.jpg";
+    const [image_src] = createResource(
+        () =>
+            song.image.length > 0
+                ? song.image[song.image.length - 1].url
+                : FALLBACK_IMAGE,
+        imageSource,
+    );
     const name = song.name || "Unknown";
     const artists = song.artists.primary.map((artist) => artist.name).join(
         ", ",
@@ -40,7 +60,7 @@ function Card({
         >
             <figure class="absolute inset-0 rounded-lg ">
                 <img
-                    src={image_src}
+                    src={image_src() ?? FALLBACK_IMAGE}
                     alt={`${name} album cover`}
                     class="w-full h-full object-cover opacity-90 shadow-md"
                 />
@@ -29,6 +29,7 @@ export interface TrackResult {
   lyricsId?: string | null;
   url: string;
   copyright?: string | null;
+  isrc?: string | null;
   album: Album;
   artists: Artists;
   image: ImageItem[];
@@ -65,3 +66,127 @@ export interface DownloadUrlItem {
   quality: string;
   url: string;
 }
+
+export type MatchResult =
+  | {
+    status: "match";
+    track: TrackResult;
+    confidence: number;
+    alignedHashes: number;
+    offsetSecs: number;
+    speed: number;
+  }
+  | { status: "noMatch" };
+
+export type IngestResult =
+  | { status: "added" }
+  | { status: "duplicate"; existingId: string; confidence: number }
+  | { status: "aliased"; existingId: string; confidence: number };
+
+export type FileReport = { path: string } & (
+  | { outcome: "ingested"; id: string; result: IngestResult }
+  | { outcome: "failed"; error: string }
+);
+
+export type JobRequest =
+  | { source: "jiosaavn"; track: TrackResult }
+  | { source: "youtube"; url: string };
+
+export type JobStage =
+  | { stage: "queued" }
+  | { stage: "downloading"; progress: number | null }
+  | { stage: "decoding" }
+  | { stage: "fingerprinting" }
+  | { stage: "storing" }
+  | { stage: "done"; result: IngestResult }
+  | { stage: "failed"; error: string }
+  | { stage: "cancelled" };
+
+export type JobInfo = {
+  id: number;
+  label: string;
+  request: JobRequest;
+} & JobStage;
+
+export type StoreBackend = "redis" | "sled" | "memory";
+
+export type RetryConfig = {
+  maxAttempts: number;
+  minDelayMs: number;
+  maxDelayMs: number;
+  jitter: boolean;
+  timeoutSecs: number;
+};
+
+export type MatcherConfig = {
+  minConfidence: number;
+  minAlignedHashes: number;
+  maxOccurrences: number;
+  maxSpeedChange: number;
+  duplicateConfidence: number;
+};
+
+export type DuplicatePolicy = "reject" | "alias";
+
+export type FrequencyScale =
+  | { type: "linear" }
+  | {
+    type: "mel";
+    filters: number;
+    minFreq: number;
+    maxFreq: number;
+    bands: number;
+  };
+
+export type PeakStrategy =
+  | { type: "bandMax" }
+  | {
+    type: "constellation";
+    timeRadius: number;
+    freqRadius: number;
+    threshold: number;
+    peaksPerSecond: number;
+  };
+
+export type PairingStrategy =
+  | { type: "nearest" }
+  | { type: "targetZone"; delay: number; length: number; height: number }
+  | { type: "triplets"; delay: number; length: number; height: number };
+
+export type FingerprintConfig = {
+  version: number;
+  fftSize: number;
+  hopSize: number;
+  bands: [number, number][];
+  fanOut: number;
+  peakThreshold: number;
+  targetSampleRate: number;
+  peakStrategy: PeakStrategy;
+  pairingStrategy: PairingStrategy;
+  frequencyScale: FrequencyScale;
+};
+
+export type Settings = {
+  storeBackend: StoreBackend;
+  redisUri: string;
+  musicFinderApiUrl: string;
+  jiosaavnApiUrl: string;
+  musicDownloaderApiUrl: string;
+  retry: RetryConfig;
+  matcher: MatcherConfig;
+  duplicatePolicy: DuplicatePolicy;
+  fingerprint: FingerprintConfig;
+};
+
+export type SettingsIssue = { field: string; message: string };
+
+export type ConnectionStatus =
+  | { status: "connecting" }
+  | { status: "connected" }
+  | { status: "unavailable"; error: string };
+
+export type StorageStatus = ConnectionStatus & { warnings: string[] };
+
+export type CommandError =
+  | { kind: "storageUnavailable"; message: string }
+  | { kind: "failed"; message: string };